@@ -1,15 +1,43 @@
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
-use fontdue::layout::{
-    CoordinateSystem, HorizontalAlign, Layout, LayoutSettings, TextStyle,
-    VerticalAlign, WrapStyle,
+use gif::{
+    ColorOutput, DecodeOptions, DecodingError, DisposalMethod, Encoder, Frame,
+    Repeat,
 };
-use fontdue::{Font, FontSettings};
-use gif::{ColorOutput, DecodeOptions, DisposalMethod, Encoder, Repeat};
 use rgb::RGB;
 use wu_quantization::compress;
 
-const SCALE: f32 = 0.3;
+mod bidi;
+mod compositor;
+mod emoji;
+mod error;
+mod fonts;
+mod hyphenate;
+mod markup;
+mod options;
+mod shaping;
+mod text;
+
+pub use compositor::Compositor;
+pub use error::CaptionError;
+pub use fonts::FontRegistry;
+pub use options::{
+    BarHeight, CaptionMode, CaptionOptions, CaptionPosition, OverflowPolicy,
+    Padding, Shadow, TextAlign, VerticalAlign, Wrap,
+};
+pub use text::Overflow;
+use text::{bar_overflow, fitted_bar_height, render_bar, Piece};
+
+// overlay text over frames that are transparent where it goes is white, and
+// stays readable thanks to its outline
+const OVERLAY_TEXT: [u8; 3] = [255; 3];
+// number of shades from the background to the text colour, both included,
+// used for smooth text without compression, and how far off a shade already
+// in the palette may be
+const RAMP_LEVELS: usize = 8;
+const MAX_RAMP_DISTANCE: i32 = 3 * 24 * 24;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
@@ -33,128 +61,459 @@ pub enum Indexer
 {
     // Wu indexer from rgb
     Wu(Box<dyn Fn([u8; 3]) -> u8>),
-    // Deduped old idx to new idx
-    Deduped(Box<dyn Fn(u8) -> u8>),
+    // nearest colour of an uncompressed palette from rgb
+    Nearest(Box<dyn Fn([u8; 3]) -> u8>),
+    // Deduped old idx to new idx, one table per palette with the global
+    // palette first and then every local palette in frame order
+    Deduped(Vec<[u8; 256]>),
     None,
 }
 
-// Find the indices of the minimum and maximum values in one
-// iteration, panics if thes's no element.
-fn minmax_ids<I, E>(mut it: I) -> (usize, usize)
+// Palette indices of a ramp of `levels` colours going from the background to
+// the text colour, both found like `colour_id`. Colours in between are added
+// the same way, or use the closest colour in the palette if it's close enough.
+fn ramp_ids<I>(
+    palette: &mut Vec<u8>,
+    mut free: I,
+    (background, text): ([u8; 3], [u8; 3]),
+    levels: usize,
+) -> Vec<u8>
 where
-    I: Iterator<Item = E>,
-    E: Ord + Copy,
+    I: Iterator<Item = usize>,
 {
-    let mut max = match it.next() {
-        Some(val) => val,
-        None => {
-            panic!("Empty array has no minmax");
+    let background_id = colour_id(palette, free.by_ref(), background);
+    let text_id = colour_id(palette, free.by_ref(), text);
+    let mut ramp = vec![background_id];
+    for i in 1..levels.max(2) - 1 {
+        let alpha = (255 * i / (levels - 1)) as u8;
+        let mut colour = background;
+        for (c, t) in colour.iter_mut().zip(text.iter()) {
+            *c = blend(*c, *t, alpha);
+        }
+        let id = match palette.chunks_exact(3).position(|x| x == colour) {
+            Some(id) => id as u8,
+            None if palette.len() < 256 * 3 => {
+                palette.extend_from_slice(&colour);
+                (palette.len() / 3 - 1) as u8
+            }
+            None => match free.next() {
+                Some(id) => {
+                    palette[id * 3..id * 3 + 3].copy_from_slice(&colour);
+                    id as u8
+                }
+                None => {
+                    let closest = nearest_indexer(palette)(colour);
+                    let distance: i32 = palette[closest as usize * 3..]
+                        .iter()
+                        .zip(colour.iter())
+                        .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                        .sum();
+                    match distance <= MAX_RAMP_DISTANCE {
+                        true => closest,
+                        false if alpha < 128 => background_id,
+                        false => text_id,
+                    }
+                }
+            },
+        };
+        ramp.push(id);
+    }
+    ramp.push(text_id);
+    ramp
+}
+
+// Relative luminance of an sRGB colour, as WCAG defines it.
+fn luminance(colour: [u8; 3]) -> f32
+{
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        match c <= 0.03928 {
+            true => c / 12.92,
+            false => ((c + 0.055) / 1.055).powf(2.4),
         }
     };
-    let (mut min_idx, mut max_idx) = (0, 0);
-    let mut pos = 1;
-    let mut min = max;
-    for x in it {
-        if x > max {
-            max_idx = pos;
-            max = x;
-        } else if x < min {
-            min_idx = pos;
-            min = x;
+    0.2126 * linear(colour[0])
+        + 0.7152 * linear(colour[1])
+        + 0.0722 * linear(colour[2])
+}
+
+// WCAG contrast ratio of two colours, from 1 to 21.
+fn contrast(a: [u8; 3], b: [u8; 3]) -> f32
+{
+    let (a, b) = (luminance(a), luminance(b));
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+// The palette colour that stands out the most against `background`. If none
+// of them reach `min_contrast`, black or white does instead.
+fn contrast_colour(
+    palette: &[u8],
+    background: [u8; 3],
+    min_contrast: f32,
+) -> [u8; 3]
+{
+    let best = palette
+        .chunks_exact(3)
+        .map(|x| [x[0], x[1], x[2]])
+        .map(|x| (x, contrast(x, background)))
+        .fold(None, |best: Option<([u8; 3], f32)>, x| match best {
+            Some(best) if best.1 >= x.1 => Some(best),
+            _ => Some(x),
+        });
+    match best {
+        Some((colour, ratio)) if ratio >= min_contrast => colour,
+        _ if contrast([0; 3], background) >= contrast([255; 3], background) => {
+            [0; 3]
         }
-        pos += 1;
+        _ => [255; 3],
     }
-    (min_idx, max_idx)
 }
 
-// Generate the section to prepend by fitting some text into
-// the designated area.
-fn make_piece<T>(
-    piece_width: u16,
-    piece_height: u16,
-    px: f32,
-    palette_idx: T,
-    text: &str,
-) -> Vec<u8>
+// Average colour of the opaque pixels of a frame that are inside the rows
+// where overlay text goes, None if there are none.
+fn backdrop(
+    frame: &Frame,
+    old_h: u16,
+    (top_h, bottom_h): (u16, u16),
+) -> Option<[u8; 3]>
+{
+    // an empty frame has no pixels to go by
+    if frame.width == 0 {
+        return None;
+    }
+    let mut sum = [0u64; 3];
+    let mut n = 0;
+    let rows = frame.buffer.chunks_exact(frame.width as usize * 4);
+    let between = top_h as usize..old_h.saturating_sub(bottom_h) as usize;
+    for (y, row) in (frame.top as usize..).zip(rows) {
+        if between.contains(&y) {
+            continue;
+        }
+        for pixel in row.chunks_exact(4).filter(|x| x[3] > 0) {
+            for (s, c) in sum.iter_mut().zip(pixel) {
+                *s += *c as u64;
+            }
+            n += 1;
+        }
+    }
+    match n {
+        0 => None,
+        n => Some([(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]),
+    }
+}
+
+// Index of a colour in the palette. A missing colour is added if there's room
+// for another one or replaces one of the `free` entries, otherwise the closest
+// colour the palette has is used.
+fn colour_id<I>(palette: &mut Vec<u8>, mut free: I, colour: [u8; 3]) -> u8
 where
-    T: Fn(u8) -> u8,
+    I: Iterator<Item = usize>,
 {
-    // TODO if px is too big or too small, change scale OR increase layout
-    // size
-
-    // prepare the font, layout, and canvas ~~~~~~~~~~~~~~~~~~~~~~~~~
-    // font
-    let font = {
-        let font = include_bytes!("../fonts/FjallaOne-Regular.ttf");
-        Font::from_bytes(
-            font.as_ref(),
-            FontSettings {
-                collection_index: 0,
-                scale: px,
-            },
-        )
-        .unwrap()
+    if let Some(id) = palette.chunks_exact(3).position(|x| x == colour) {
+        return id as u8;
+    }
+    if palette.len() < 256 * 3 {
+        palette.extend_from_slice(&colour);
+        return (palette.len() / 3 - 1) as u8;
+    }
+    match free.next() {
+        Some(id) => {
+            palette[id * 3..id * 3 + 3].copy_from_slice(&colour);
+            id as u8
+        }
+        None => nearest_indexer(palette)(colour),
+    }
+}
+
+// Indices of the first `n_colours` palette entries that no pixel of the
+// frame refers to.
+fn unused_ids(frame: &Frame, n_colours: usize) -> impl Iterator<Item = usize>
+{
+    let mut used = [false; 256];
+    frame.buffer.iter().for_each(|x| used[*x as usize] = true);
+    if let Some(t) = frame.transparent {
+        used[t as usize] = true;
+    }
+    (0..n_colours.min(256)).filter(move |x| !used[*x])
+}
+
+// A palette index for the parts of the canvas a frame without transparency
+// leaves uncovered. It can't be one the frame or its bars use: the global
+// slot for it if the frame uses the global palette and there is one, an
+// unused index, or a new entry in the frame's own palette.
+fn transparent_id(
+    frame: &mut Frame,
+    (top, bottom): &(Vec<u8>, Vec<u8>),
+    (global_colours, global_slot): (usize, Option<u8>),
+) -> Result<u8, CaptionError>
+{
+    if let (None, Some(t)) = (&frame.palette, global_slot) {
+        return Ok(t);
+    }
+    let mut used = [false; 256];
+    for x in frame.buffer.iter().chain(top).chain(bottom) {
+        used[*x as usize] = true;
+    }
+    let n_colours = match &frame.palette {
+        Some(palette) => palette.len() / 3,
+        None => global_colours,
     };
-    //layout
-    let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
-    layout.reset(&LayoutSettings {
-        x: 0.0,
-        y: 0.0,
-        max_width: Some(piece_width.into()),
-        max_height: Some(piece_height.into()),
-        horizontal_align: HorizontalAlign::Center,
-        vertical_align: VerticalAlign::Middle,
-        wrap_style: WrapStyle::Word,
-        wrap_hard_breaks: true,
-    });
-    // canvas
-    let mut canvas =
-        vec![palette_idx(0); piece_width as usize * piece_height as usize];
-
-    // "write" text to the layout
-    layout.append(&[&font], &TextStyle::new(text, px, 0));
-    //println!("Creating pre {:#?}", layout.glyphs());
-
-    // now draw 🔫 ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
-    for glyph in layout.glyphs() {
-        let (mut x, mut y, w) = (
-            glyph.x as usize,
-            glyph.y as usize,
-            glyph.width,
-            //glyph.height,
-        );
-
-        let x0 = x;
-        let w = w + x;
-        let (_, bitmap) =
-            &font.rasterize_indexed(glyph.key.glyph_index as usize, px);
-
-        //println!("{}, {}, {}, {}, {}", x, y, bitmap.len(), w, h);
-        for pixel in bitmap {
-            // when position exceeds piece width limit, reset x and move
-            // down to next line
-            if x > (piece_width - 1).into() {
-                x = x0;
-                y += 1;
+    if let Some(t) = (0..n_colours.min(256)).find(|x| !used[*x]) {
+        return Ok(t as u8);
+    }
+    match &mut frame.palette {
+        Some(palette) if palette.len() < 256 * 3 => {
+            palette.extend_from_slice(&[0, 0, 0]);
+            Ok((palette.len() / 3 - 1) as u8)
+        }
+        _ => Err(CaptionError::Unsupported(
+            "no palette entry left for the canvas around a partial frame"
+                .into(),
+        )),
+    }
+}
+
+// Grow a frame that was moved between the bars so it covers the whole canvas,
+// bars included. The space around the original frame is padded with `fill`.
+fn cover_bars(
+    frame: &mut Frame,
+    (top, bottom): (&[u8], &[u8]),
+    fill: u8,
+    (w, h): (u16, u16),
+    top_h: u16,
+)
+{
+    let (w, h) = (w as usize, h as usize);
+    let (left, width) = (frame.left as usize, frame.width as usize);
+    // a frame starting below the canvas is cut off anyway
+    let gap = ((frame.top - top_h) as usize).min(h);
+
+    let mut buffer = Vec::with_capacity(w * h);
+    buffer.extend_from_slice(top);
+    buffer.resize(buffer.len() + gap * w, fill);
+    // an empty frame has no rows, only the bars and what's around them
+    for row in frame.buffer.chunks(width.max(1)) {
+        let start = buffer.len();
+        buffer.resize(start + left.min(w), fill);
+        buffer.extend(row.iter().take(w.saturating_sub(left)));
+        buffer.resize(start + w, fill);
+    }
+    // pad (or cut) down to where the bottom bar starts
+    buffer.resize(w * h - bottom.len(), fill);
+    buffer.extend_from_slice(bottom);
+
+    frame.left = 0;
+    frame.top = 0;
+    frame.width = w as u16;
+    frame.height = h as u16;
+    frame.buffer = buffer.into();
+}
+
+// Index of the palette colour closest to an rgb colour, colours that are in
+// the palette are looked up directly.
+fn nearest_indexer(palette: &[u8]) -> impl Fn([u8; 3]) -> u8
+{
+    let colours: Vec<[u8; 3]> = palette
+        .chunks_exact(3)
+        .map(|x| [x[0], x[1], x[2]])
+        .collect();
+    let exact: HashMap<[u8; 3], u8> = colours
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (*x, i as u8))
+        .collect();
+
+    move |x| match exact.get(&x) {
+        Some(i) => *i,
+        None => {
+            let distance = |y: &[u8; 3]| -> u32 {
+                x.iter()
+                    .zip(y.iter())
+                    .map(|(a, b)| (*a as i32 - *b as i32).pow(2) as u32)
+                    .sum()
+            };
+            (0..colours.len())
+                .min_by_key(|i| distance(&colours[*i]))
+                .unwrap_or(0) as u8
+        }
+    }
+}
+
+// Mix two colour channels, `alpha` being how much of `over` to use.
+fn blend(under: u8, over: u8, alpha: u8) -> u8
+{
+    let alpha = alpha as u16;
+    ((under as u16 * (255 - alpha) + over as u16 * alpha) / 255) as u8
+}
+
+// The colours captions are drawn with.
+struct Colours
+{
+    background: [u8; 3],
+    text: [u8; 3],
+    outline: [u8; 3],
+    shadow: [u8; 3],
+}
+
+// Colour and alpha of every layer of a pixel of the piece, bottom first.
+fn layers(piece: &Piece, i: usize, colours: &Colours) -> [([u8; 3], u8); 4]
+{
+    let glyph = piece.colour.get(i).copied().unwrap_or([0; 4]);
+    [
+        (colours.shadow, piece.shadow.get(i).copied().unwrap_or(0)),
+        (colours.outline, piece.outline.get(i).copied().unwrap_or(0)),
+        (colours.text, piece.coverage[i]),
+        ([glyph[0], glyph[1], glyph[2]], glyph[3]),
+    ]
+}
+
+// Colour of a bar pixel, all the layers over the bar's background.
+fn bar_colour(piece: &Piece, i: usize, colours: &Colours) -> [u8; 3]
+{
+    let mut pixel = colours.background;
+    for (colour, alpha) in layers(piece, i, colours) {
+        for (p, c) in pixel.iter_mut().zip(colour.iter()) {
+            *p = blend(*p, *c, alpha);
+        }
+    }
+    pixel
+}
+
+// Every colour the outlines, shadows and colour glyphs add to the bars, or to
+// the frames for overlays.
+fn drawn_colours(
+    pieces: &(Piece, Piece),
+    colours: &Colours,
+    overlay: bool,
+) -> Vec<[u8; 3]>
+{
+    let mut drawn: Vec<[u8; 3]> = Vec::new();
+    for piece in [&pieces.0, &pieces.1] {
+        for (i, glyph) in piece.colour.iter().enumerate() {
+            if glyph[3] > 0 {
+                drawn.push(match overlay {
+                    true => [glyph[0], glyph[1], glyph[2]],
+                    false => bar_colour(piece, i, colours),
+                });
+            }
+        }
+        if piece.outline.iter().any(|x| *x > 0) {
+            drawn.push(colours.outline);
+        }
+        if piece.shadow.iter().any(|x| *x > 0) {
+            drawn.push(colours.shadow);
+        }
+    }
+    drawn.sort_unstable();
+    drawn.dedup();
+    drawn
+}
+
+// Blend styled text onto the rgba canvas, starting at the first pixel of
+// `top` and covering as many full rows as the piece has.
+fn draw_overlay(canvas: &mut [u8], top: usize, piece: &Piece, colours: &Colours)
+{
+    let pixels = canvas.chunks_exact_mut(4).skip(top);
+    for (i, pixel) in pixels.take(piece.coverage.len()).enumerate() {
+        for (colour, alpha) in layers(piece, i, colours) {
+            if alpha == 0 {
                 continue;
             }
-            // when position exceeds y limit, there's nothing more to draw
-            if y > (piece_height - 1).into() {
-                break;
+            // there's nothing to blend with on transparent pixels
+            if pixel[3] == 0 {
+                if alpha > 127 {
+                    pixel.copy_from_slice(&[
+                        colour[0], colour[1], colour[2], 255,
+                    ]);
+                }
+                continue;
             }
-            // get x, y coordinate and draw pixel...
-            canvas[x + y * piece_width as usize] = palette_idx(*pixel);
-
-            // advance x
-            x += 1;
-            if x == w {
-                x = x0;
-                y += 1;
+            for (p, c) in pixel.iter_mut().zip(colour.iter()) {
+                *p = blend(*p, *c, alpha);
             }
         }
     }
+}
+
+// Index a piece: colour glyphs use the closest colour in the palette, text
+// is drawn with `ramp` (see `ramp_ids`), and outlines and shadows with their
+// own index where the text isn't.
+fn index_piece(
+    piece: &Piece,
+    ramp: &[u8],
+    (outline, shadow): (u8, u8),
+    nearest: &dyn Fn(usize) -> u8,
+) -> Vec<u8>
+{
+    let steps = ramp.len() - 1;
+    (0..piece.coverage.len())
+        .map(|i| {
+            let glyph = piece.colour.get(i).map_or(0, |g| g[3]);
+            // without greys, anything a bit covered is text
+            let step = match steps {
+                1 if piece.coverage[i] > 30 => 1,
+                _ => (piece.coverage[i] as usize * steps + 127) / 255,
+            };
+            if glyph > 0 {
+                nearest(i)
+            } else if step > 0 {
+                ramp[step]
+            } else if piece.outline.get(i).copied().unwrap_or(0) > 127 {
+                outline
+            } else if piece.shadow.get(i).copied().unwrap_or(0) > 127 {
+                shadow
+            } else {
+                ramp[0]
+            }
+        })
+        .collect()
+}
+
+// Index both bars for a palette, adding the colours to it as needed: text is
+// anti-aliased with a few shades if `smooth`, and outlines, shadows and
+// coloured spans get their colours like `colour_id`. Colour glyphs use the
+// closest colours the palette has.
+fn text_pieces<I>(
+    pieces: &(Piece, Piece),
+    palette: &mut Vec<u8>,
+    mut free: I,
+    colours: &Colours,
+    smooth: bool,
+) -> (Vec<u8>, Vec<u8>)
+where
+    I: Iterator<Item = usize>,
+{
+    let levels = if smooth { RAMP_LEVELS } else { 2 };
+    let ramp = ramp_ids(
+        palette,
+        free.by_ref(),
+        (colours.background, colours.text),
+        levels,
+    );
+    let drawn = |layer: fn(&Piece) -> &Vec<u8>| {
+        [&pieces.0, &pieces.1]
+            .iter()
+            .any(|piece| layer(piece).iter().any(|x| *x > 0))
+    };
+    let outline = match drawn(|piece| &piece.outline) {
+        true => colour_id(palette, free.by_ref(), colours.outline),
+        false => 0,
+    };
+    let shadow = match drawn(|piece| &piece.shadow) {
+        true => colour_id(palette, free.by_ref(), colours.shadow),
+        false => 0,
+    };
+    // coloured spans have to be in there for `nearest` to find them
+    for colour in pieces.0.span_colours.iter().chain(&pieces.1.span_colours) {
+        colour_id(palette, free.by_ref(), *colour);
+    }
 
-    canvas
+    let nearest = nearest_indexer(palette);
+    let index = |piece: &Piece| {
+        let nearest = |i| nearest(bar_colour(piece, i, colours));
+        index_piece(piece, &ramp, (outline, shadow), &nearest)
+    };
+    (index(&pieces.0), index(&pieces.1))
 }
 
 pub fn palette_to_rgb(palette: &[u8]) -> Vec<RGB<u8>>
@@ -165,29 +524,151 @@ pub fn palette_to_rgb(palette: &[u8]) -> Vec<RGB<u8>>
         .collect()
 }
 
+// Merge palettes into one, dropping repeated colours. The `transparent`
+// indices of every palette, none for those past its end, are never merged,
+// or every pixel of the same colour would become see-through. Returns the
+// merged palette and, for every input palette, a table from old to new
+// index. None if the merged palette doesn't fit into 256 colours.
+fn dedup_palettes(
+    palettes: &[Vec<u8>],
+    transparent: &[Vec<u8>],
+) -> Option<(Vec<u8>, Vec<[u8; 256]>)>
+{
+    let mut merged = Vec::new();
+    let mut ids: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut tables = Vec::with_capacity(palettes.len());
+
+    for (i, palette) in palettes.iter().enumerate() {
+        let transparent = transparent.get(i).map_or(&[][..], |x| x);
+        let mut table = [0; 256];
+        for (old, colour) in palette.chunks_exact(3).take(256).enumerate() {
+            let colour = [colour[0], colour[1], colour[2]];
+            let new = match ids.get(&colour) {
+                Some(new) if !transparent.contains(&(old as u8)) => *new,
+                _ => {
+                    if merged.len() == 256 * 3 {
+                        return None;
+                    }
+                    let new = (merged.len() / 3) as u8;
+                    if !transparent.contains(&(old as u8)) {
+                        ids.insert(colour, new);
+                    }
+                    merged.extend_from_slice(&colour);
+                    new
+                }
+            };
+            table[old] = new;
+        }
+        tables.push(table);
+    }
+
+    Some((merged, tables))
+}
+
+// Compress the colours with Wu, keeping slots out of Wu's reach for the
+// `reserved` colours, which have to come out exactly, and room for a
+// transparent slot if needed.
+fn wu_palette(
+    all_colours: Vec<RGB<u8>>,
+    number: usize,
+    transparent: bool,
+    reserved: &[[u8; 3]],
+) -> (Vec<u8>, Indexer, bool)
+{
+    let n_colours = number.saturating_sub(transparent as usize + reserved.len());
+    let (mut p, i) = compress(all_colours, n_colours.max(1));
+    let first_reserved = p.len() / 3;
+    p.extend_from_slice(&reserved.concat());
+    let reserved = reserved.to_vec();
+    (
+        p,
+        Indexer::Wu(Box::new(move |x| {
+            match reserved.iter().position(|r| *r == x) {
+                Some(pos) => (first_reserved + pos) as u8,
+                None => *i.rgb_index(RGB::new(x[0] >> 3, x[1] >> 3, x[2] >> 3)),
+            }
+        })),
+        transparent,
+    )
+}
+
+// Returns the new global palette, the indexer for it, and whether the global
+// palette needs a slot for transparent pixels, which goes after the colours
+// of the bars. Indexers that work on composited rgba frames need one if the
+// gif has any transparent pixels, the others for frames that leave parts of
+// the canvas uncovered without being transparent themselves. Overlays draw
+// straight onto the frames, so they always need rgba frames and a global
+// palette. The `extra` colours that get drawn are part of that palette, and
+// of Wu's, which keeps slots for the `reserved` ones.
 fn process_palatte<R>(
     mut decoder: gif::Decoder<R>,
     comprssion: ColourCompression,
-) -> (Vec<u8>, Indexer)
+    overlay: bool,
+    (extra, reserved): (&[[u8; 3]], &[[u8; 3]]),
+) -> Result<(Vec<u8>, Indexer, bool), CaptionError>
 where
     //T: Fn(u8) -> u8,
-    R: Read + Copy,
+    R: Read,
 {
+    let has_global = decoder.global_palette().is_some();
     // default global palette is just black and white
     let global_palette = decoder
         .global_palette()
         .unwrap_or(&[255, 255, 255, 0, 0, 0])
         .to_vec();
 
-    if let ColourCompression::Wu(number) = comprssion {
-        let mut all_colours = palette_to_rgb(&global_palette);
+    let (w, h) = (decoder.width(), decoder.height());
+    // transparency either comes from the frames themselves or from the parts
+    // of the canvas that partial frames leave uncovered
+    let (mut transparent, mut uncovered) = (false, false);
 
-        while let Some(frame) = decoder.next_frame_info().unwrap() {
-            if let Some(p) = &frame.palette {
-                all_colours.extend(palette_to_rgb(p));
+    // global first, then the local palettes in frame order, with the
+    // indices frames use as transparent in each
+    let mut palettes = vec![global_palette];
+    let mut see_through = vec![Vec::new()];
+    while let Some(frame) = decoder.next_frame_info()? {
+        let partial = frame.left > 0
+            || frame.top > 0
+            || frame.width < w
+            || frame.height < h;
+        transparent |= frame.transparent.is_some() || partial;
+        uncovered |= frame.transparent.is_none() && partial;
+        match &frame.palette {
+            Some(p) => {
+                palettes.push(p.clone());
+                see_through.push(Vec::new());
             }
+            // the rgba decoder can't colour a frame without any palette
+            None if !has_global
+                && (overlay
+                    || matches!(comprssion, ColourCompression::Wu(_))) =>
+            {
+                return Err(CaptionError::Unsupported(
+                    "frame has no colour table".into(),
+                ));
+            }
+            None => {}
+        }
+        if let Some(t) = frame.transparent {
+            let palette = match frame.palette {
+                Some(_) => palettes.len() - 1,
+                None => 0,
+            };
+            see_through[palette].push(t);
         }
+    }
 
+    let wu = matches!(comprssion, ColourCompression::Wu(_));
+    if (overlay || wu) && !extra.is_empty() {
+        palettes.push(extra.concat());
+    }
+    if (overlay || wu) && !reserved.is_empty() {
+        palettes.push(reserved.concat());
+    }
+    let all_colours: Vec<RGB<u8>> =
+        palettes.iter().flat_map(|p| palette_to_rgb(p)).collect();
+
+    if let ColourCompression::Wu(number) = comprssion {
         // if combined palette does not exceed number, it's better to do nothing
         let mut unique = all_colours.clone();
         unique.sort_unstable();
@@ -195,155 +676,421 @@ where
 
         if unique.len() > number as usize {
             drop(unique);
-            drop(global_palette);
-            let (p, i) = compress(all_colours, number as usize);
-            return (
-                p,
-                Indexer::Wu(Box::new(move |x| {
-                    *i.rgb_index(RGB::new(x[0] >> 3, x[1] >> 3, x[2] >> 3))
-                })),
-            );
+            drop(palettes);
+            return Ok(wu_palette(
+                all_colours,
+                number as usize,
+                transparent,
+                reserved,
+            ));
         }
     }
-    //TODO if colour count is small enough, we can add black/white
-    //TODO global palette can often be dedupped
-    (global_palette.to_vec(), Indexer::None)
+
+    if overlay {
+        // everything goes into one palette, Wu only steps in if it's too big
+        return Ok(match dedup_palettes(&palettes, &[]) {
+            Some((merged, _))
+                if merged.len() / 3 + (transparent as usize) <= 256 =>
+            {
+                let indexer = nearest_indexer(&merged);
+                (merged, Indexer::Nearest(Box::new(indexer)), transparent)
+            }
+            _ => wu_palette(all_colours, 256, transparent, reserved),
+        });
+    }
+
+    // local palettes are merged into the global one when they fit
+    if palettes.len() > 1 {
+        if let Some((merged, tables)) = dedup_palettes(&palettes, &see_through) {
+            return Ok((merged, Indexer::Deduped(tables), uncovered));
+        }
+    }
+    Ok((palettes.swap_remove(0), Indexer::None, uncovered))
 }
 
-pub fn caption<R: Read + Copy>(
-    _name: &str,
-    bytes: R,
-    caption: &str,
-    compression: ColourCompression,
-    scale: Option<f32>,
-    font_size: Option<f32>,
-    //smooth_font: bool, TODO
-) -> Vec<u8>
+pub fn caption(
+    bytes: &[u8],
+    options: &CaptionOptions,
+) -> Result<Vec<u8>, CaptionError>
 {
+    let mut out_image = Vec::new();
+    caption_stream(Cursor::new(bytes), &mut out_image, options)?;
+    Ok(out_image)
+}
+
+/// Check whether the caption fits into the bars of a gif before captioning
+/// it, only the header of the gif is read. Returns what doesn't fit of the
+/// top and bottom bar, regardless of `options.overflow`.
+pub fn caption_overflow(
+    bytes: &[u8],
+    options: &CaptionOptions,
+) -> Result<(Option<Overflow>, Option<Overflow>), CaptionError>
+{
+    let decoder = DecodeOptions::new().read_info(bytes)?;
+    let (w, old_h) = (decoder.width(), decoder.height());
+    let options = CaptionOptions {
+        overflow: OverflowPolicy::Clip,
+        ..options.clone()
+    };
+    let (top_text, bottom_text) = bar_texts(&options);
+    let (top_h, bottom_h) = bar_heights(w, old_h, &options)?;
+    Ok((
+        bar_overflow(w, top_h, top_text, &options)?,
+        bar_overflow(w, bottom_h, bottom_text, &options)?,
+    ))
+}
+
+// the caption bars above and below the gif, either can be left out
+fn bar_texts(options: &CaptionOptions) -> (Option<&str>, Option<&str>)
+{
+    match options.position {
+        CaptionPosition::Top => (Some(options.text.as_str()), None),
+        CaptionPosition::Bottom => (None, Some(options.text.as_str())),
+        CaptionPosition::Both => (
+            Some(options.text.as_str()),
+            Some(options.bottom_text.as_str()),
+        ),
+    }
+}
+
+// Height of the top and bottom bar, 0 for a bar that's left out. Overflowing
+// text makes the bars grow or fails, as the options ask for.
+fn bar_heights(
+    w: u16,
+    old_h: u16,
+    options: &CaptionOptions,
+) -> Result<(u16, u16), CaptionError>
+{
+    let overlay = options.mode == CaptionMode::Overlay;
+    // bars can't be taller than the limits, or the gif for overlays
+    let max = match overlay {
+        true => options.max_bar_height.unwrap_or(old_h).min(old_h),
+        false => options.max_bar_height.unwrap_or(u16::MAX),
+    };
+    let min = options.min_bar_height.unwrap_or(0).min(max);
+
+    let height = |text: Option<&str>| -> Result<u16, CaptionError> {
+        let text = match text {
+            Some(text) => text,
+            None => return Ok(0),
+        };
+        let bar_height = match options.bar_height {
+            BarHeight::Scale(scale) => (old_h as f32 * scale) as u16,
+            BarHeight::Pixels(px) => px,
+            BarHeight::Fit { .. } => fitted_bar_height(w, text, options)?,
+        }
+        .clamp(min, max);
+        if bar_height == 0 {
+            return Err(CaptionError::Layout(
+                "caption bar has no height".into(),
+            ));
+        }
+
+        let overflow = match options.overflow {
+            OverflowPolicy::Clip => None,
+            _ => bar_overflow(w, bar_height, Some(text), options)?,
+        };
+        match (overflow, options.overflow) {
+            (None, _) => Ok(bar_height),
+            (Some(overflow), OverflowPolicy::Grow) => {
+                match overflow.suggested_bar_height {
+                    Some(h) if h <= max => Ok(h),
+                    _ => Err(CaptionError::Overflow(overflow)),
+                }
+            }
+            (Some(overflow), _) => Err(CaptionError::Overflow(overflow)),
+        }
+    };
+    let (top_text, bottom_text) = bar_texts(options);
+    Ok((height(top_text)?, height(bottom_text)?))
+}
+
+/// Caption a gif read from `input`, writing every frame to `output` as soon
+/// as it's encoded. The input is read twice, once to collect the palettes
+/// and once for the frames, so it has to be seekable. Neither the input nor
+/// the output are ever held in memory as a whole.
+pub fn caption_stream<R, W>(
+    mut input: R,
+    output: W,
+    options: &CaptionOptions,
+) -> Result<(), CaptionError>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let start = input.stream_position().map_err(DecodingError::from)?;
+
     let mut decoder_opts = DecodeOptions::new();
     decoder_opts.set_color_output(ColorOutput::RGBA);
-    let decoder = decoder_opts.read_info(bytes).unwrap();
+    let mut decoder = decoder_opts.read_info(&mut input)?;
 
     let w = decoder.width();
     let old_h = decoder.height();
+    if w == 0 || old_h == 0 {
+        return Err(CaptionError::Unsupported("gif has no pixels".into()));
+    }
 
-    // global palette and optional indexer if compressed
-    let (global_palette, indexer) = process_palatte(decoder, compression);
+    let overlay = options.mode == CaptionMode::Overlay;
+    let (top_text, bottom_text) = bar_texts(options);
+    let (top_h, bottom_h) = bar_heights(w, old_h, options)?;
+    let h = match overlay {
+        true => Some(old_h),
+        false => old_h
+            .checked_add(top_h)
+            .and_then(|h| h.checked_add(bottom_h)),
+    }
+    .ok_or_else(|| {
+        CaptionError::Unsupported("gif is too tall to caption".into())
+    })?;
 
-    let (h, piece) = {
-        let piece_height = (old_h as f32 * scale.unwrap_or(SCALE)) as u16;
-        let h = old_h + piece_height;
+    let coverage = (
+        render_bar(w, top_h, top_text, options)?,
+        render_bar(w, bottom_h, bottom_text, options)?,
+    );
 
-        // if a px_size is provided, we use that, otherwise we calculate it
-        // from the area per char available (tho it's better to use graphemes)
-        let px = {
-            if let Some(px) = font_size {
-                px
-            } else {
-                let n_chars = caption.chars().count() as f32;
-                let text_area = w as usize * piece_height as usize;
-                let area_per_char = text_area as f32 / n_chars;
-                area_per_char.sqrt() as f32
+    // automatic text colours come from the gif's own colours, and overlays
+    // pick theirs against the first frame where the text goes
+    let palette = decoder.global_palette().unwrap_or(&[]).to_vec();
+    let text_colour = match options.text_colour {
+        Some(colour) => colour,
+        None if overlay => {
+            let behind = decoder
+                .read_next_frame()?
+                .and_then(|frame| backdrop(frame, old_h, (top_h, bottom_h)));
+            drop(decoder);
+            input
+                .seek(SeekFrom::Start(start))
+                .map_err(DecodingError::from)?;
+            let mut decoder_opts = DecodeOptions::new();
+            decoder_opts.set_color_output(ColorOutput::RGBA);
+            decoder = decoder_opts.read_info(&mut input)?;
+            match behind {
+                Some(behind) => {
+                    contrast_colour(&palette, behind, options.min_contrast)
+                }
+                None => OVERLAY_TEXT,
             }
-        };
+        }
+        None => contrast_colour(
+            &palette,
+            options.background_colour,
+            options.min_contrast,
+        ),
+    };
 
-        let piece = match &indexer {
-            Indexer::Wu(indexer) => make_piece(
-                w,
-                piece_height,
-                px,
-                |x| indexer([255 - x; 3]),
-                caption,
-            ),
-            Indexer::Deduped(indexer) => {
-                make_piece(w, piece_height, px, indexer, caption)
-            }
-            Indexer::None => {
-                let basic_indexer = {
-                    let (black, white) = minmax_ids(
-                        // sum chunks of 3 to get the absolute brightness
-                        global_palette.chunks(3).map(|x| {
-                            x.iter().map(|y| *y as usize).sum::<usize>()
-                        }),
-                    );
-                    move |x| if x > 30 { black as u8 } else { white as u8 }
-                };
-                make_piece(w, piece_height, px, basic_indexer, caption)
-            }
-        };
+    let colours = Colours {
+        background: options.background_colour,
+        text: text_colour,
+        outline: options.outline_colour,
+        shadow: options.shadow.map_or([0; 3], |shadow| shadow.colour),
+    };
+    // colours drawn onto the gif besides the text and bars, which have to
+    // come out exactly
+    let extra = drawn_colours(&coverage, &colours, overlay);
+    let mut reserved = match overlay {
+        true => vec![colours.text],
+        false => vec![colours.background, colours.text],
+    };
+    reserved.extend(&coverage.0.span_colours);
+    reserved.extend(&coverage.1.span_colours);
+    reserved.sort_unstable();
+    reserved.dedup();
+
+    // global palette and optional indexer if compressed
+    let (mut global_palette, indexer, needs_transparent) = process_palatte(
+        decoder,
+        options.compression,
+        overlay,
+        (&extra, &reserved),
+    )?;
 
-        (h, piece)
+    // overlays are drawn onto every frame, bars are indexed once
+    let pieces = match &indexer {
+        _ if overlay => Default::default(),
+        Indexer::Wu(indexer) | Indexer::Nearest(indexer) => {
+            let index = |piece: &Piece| -> Vec<u8> {
+                (0..piece.coverage.len())
+                    .map(|i| indexer(bar_colour(piece, i, &colours)))
+                    .collect()
+            };
+            (index(&coverage.0), index(&coverage.1))
+        }
+        // deduped palettes are merged into the global one, so both draw the
+        // pieces with the global palette
+        Indexer::Deduped(_) | Indexer::None => text_pieces(
+            &coverage,
+            &mut global_palette,
+            std::iter::empty(),
+            &colours,
+            options.smooth_font,
+        ),
+    };
+    // the transparent slot comes after the colours of the bars, so it's never
+    // one of them
+    let transparent = match needs_transparent {
+        true if global_palette.len() < 256 * 3 => {
+            global_palette.extend_from_slice(&[0, 0, 0]);
+            Some((global_palette.len() / 3 - 1) as u8)
+        }
+        _ => None,
     };
-    let shift_h = h - old_h;
 
-    let mut out_image = Vec::new();
-    let mut encoder =
-        { Encoder::new(&mut out_image, w, h, &global_palette).unwrap() };
-    encoder.set_repeat(Repeat::Infinite).unwrap();
+    let mut encoder = { Encoder::new(output, w, h, &global_palette)? };
+    encoder.set_repeat(Repeat::Infinite)?;
 
+    // back to the start for the second pass
+    input
+        .seek(SeekFrom::Start(start))
+        .map_err(DecodingError::from)?;
     let mut decoder_opts = DecodeOptions::new();
 
     match indexer {
-        Indexer::Wu(indexer) => {
+        Indexer::Wu(indexer) | Indexer::Nearest(indexer) => {
             decoder_opts.set_color_output(ColorOutput::RGBA);
-            let mut decoder = decoder_opts.read_info(bytes).unwrap();
-
-            while let Some(old_frame) = decoder.read_next_frame().unwrap() {
-                let mut new_frame = old_frame.clone();
-
-                let triplets: Vec<[u8; 3]> = old_frame
-                    .buffer
-                    .chunks_exact(4)
-                    .map(|x| [x[0], x[1], x[2]])
-                    .collect();
+            let mut decoder = decoder_opts.read_info(&mut input)?;
+            let mut compositor = Compositor::new(w, old_h);
 
-                let mut new_buff = Vec::with_capacity(triplets.len());
+            // every frame is drawn whole, so the partial frames and disposal
+            // methods of the input don't matter anymore. Frames with holes
+            // in them are cleared away, so they don't show the one before.
+            let dispose = match transparent {
+                Some(_) => DisposalMethod::Background,
+                None => DisposalMethod::Keep,
+            };
+            while let Some(old_frame) = decoder.read_next_frame()? {
+                let mut canvas = Cow::from(compositor.composite(old_frame));
+                if overlay {
+                    let canvas = canvas.to_mut();
+                    let bottom = (old_h - bottom_h) as usize * w as usize;
+                    draw_overlay(canvas, 0, &coverage.0, &colours);
+                    draw_overlay(canvas, bottom, &coverage.1, &colours);
+                }
 
-                triplets.iter().for_each(|x| {
-                    new_buff.push(indexer(*x));
+                let mut new_buff = Vec::with_capacity(w as usize * h as usize);
+                new_buff.extend_from_slice(&pieces.0);
+                canvas.chunks_exact(4).for_each(|x| {
+                    new_buff.push(match transparent {
+                        Some(t) if x[3] == 0 => t,
+                        _ => indexer([x[0], x[1], x[2]]),
+                    });
                 });
+                new_buff.extend_from_slice(&pieces.1);
 
-                new_frame.palette = None;
-                new_frame.height = h;
-                new_frame.buffer = [piece.clone(), new_buff].concat().into();
-                encoder.write_frame(&new_frame).unwrap();
+                let new_frame = Frame {
+                    delay: old_frame.delay,
+                    dispose,
+                    transparent,
+                    width: w,
+                    height: h,
+                    buffer: new_buff.into(),
+                    ..Frame::default()
+                };
+                encoder.write_frame(&new_frame)?;
             }
         }
-        Indexer::Deduped(_) => todo!(),
-        Indexer::None => {
+        Indexer::Deduped(_) | Indexer::None => {
             decoder_opts.set_color_output(ColorOutput::Indexed);
-            let mut decoder = decoder_opts.read_info(bytes).unwrap();
+            let mut decoder = decoder_opts.read_info(&mut input)?;
 
-            // well... I learned about disposal methods smh
-            let mut previous_disposal = DisposalMethod::Background;
+            // The frames are moved in between the bars, so disposing of them
+            // won't touch those. Only frames that were grown to draw the bars
+            // can take them away again, and the next frame has to redraw them.
+            let mut bars_drawn = false;
+            // which of the deduped tables belongs to the next local palette
+            let mut local_idx = 0;
 
-            while let Some(old_frame) = decoder.read_next_frame().unwrap() {
+            while let Some(old_frame) = decoder.read_next_frame()? {
                 let mut new_frame = old_frame.clone();
+                // the buffer is always written out in order
+                new_frame.interlaced = false;
 
-                // if the disposal method is not Keep, we need to re-add piece
-                match previous_disposal {
-                    DisposalMethod::Keep | DisposalMethod::Previous => {
-                        new_frame.top += shift_h;
-                    }
-                    _ => {
-                        // TODO if frame uses local palette, colours need to be
-                        // adjusted... this is a pain because, for consistent results, every 
-                        // local palette needs to be processed to make sure it either has
-                        // black and white, or less than 255 colours. 
-                        //
-                        // Alternatively the piece can be made transparent?????
-                        new_frame.height = h;
-                        new_frame.buffer =
-                            [piece.as_ref(), new_frame.buffer.as_ref()]
-                                .concat()
-                                .into();
-                    }
+                // remap onto the merged global palette
+                if let Indexer::Deduped(tables) = &indexer {
+                    let table = if new_frame.palette.take().is_some() {
+                        local_idx += 1;
+                        &tables[local_idx]
+                    } else {
+                        &tables[0]
+                    };
+                    new_frame.buffer = new_frame
+                        .buffer
+                        .iter()
+                        .map(|x| table[*x as usize])
+                        .collect::<Vec<u8>>()
+                        .into();
+                    new_frame.transparent =
+                        new_frame.transparent.map(|x| table[x as usize]);
+                }
+
+                // the decoder doesn't check that frames are on the canvas
+                new_frame.top =
+                    new_frame.top.checked_add(top_h).ok_or_else(|| {
+                        CaptionError::Unsupported(
+                            "frame is too far below the gif to caption".into(),
+                        )
+                    })?;
+                if !bars_drawn {
+                    let n_colours = match &new_frame.palette {
+                        Some(palette) => palette.len() / 3,
+                        None => global_palette.len() / 3,
+                    };
+                    let free = unused_ids(&new_frame, n_colours);
+
+                    // frames with their own palette get their own black
+                    // and white, every other frame uses the global ones
+                    let local_pieces;
+                    let pieces = match &mut new_frame.palette {
+                        Some(palette) => {
+                            local_pieces = text_pieces(
+                                &coverage,
+                                palette,
+                                free,
+                                &colours,
+                                options.smooth_font,
+                            );
+                            &local_pieces
+                        }
+                        None => &pieces,
+                    };
+
+                    // anything between the bars and a partial frame has to be
+                    // transparent to keep what's already on the canvas
+                    let partial = new_frame.left > 0
+                        || new_frame.top > top_h
+                        || new_frame.width < w
+                        || (new_frame.top as u32 + new_frame.height as u32)
+                            < (top_h + old_h) as u32;
+                    let fill = match new_frame.transparent {
+                        Some(t) => t,
+                        // nothing gets filled in
+                        None if !partial => 0,
+                        None => {
+                            let t = transparent_id(
+                                &mut new_frame,
+                                pieces,
+                                (global_palette.len() / 3, transparent),
+                            )?;
+                            new_frame.transparent = Some(t);
+                            t
+                        }
+                    };
+                    cover_bars(
+                        &mut new_frame,
+                        (&pieces.0, &pieces.1),
+                        fill,
+                        (w, h),
+                        top_h,
+                    );
+                    // disposing of this frame takes the bars with it
+                    bars_drawn = matches!(
+                        new_frame.dispose,
+                        DisposalMethod::Keep | DisposalMethod::Any
+                    );
                 }
-                previous_disposal = new_frame.dispose;
-                encoder.write_frame(&new_frame).unwrap();
+                encoder.write_frame(&new_frame)?;
             }
         }
     }
     drop(encoder);
-    out_image
+    Ok(())
 }
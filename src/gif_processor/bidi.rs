@@ -0,0 +1,92 @@
+use std::ops::Range;
+
+use unicode_bidi::{bidi_class, BidiClass, BidiInfo};
+
+// A line in visual order: its runs, and whether each of them is right to
+// left, and whether the paragraph the line is in runs right to left.
+pub struct LineRuns
+{
+    pub runs: Vec<(Range<usize>, bool)>,
+    pub rtl: bool,
+}
+
+// Split every line into runs of one direction with the Unicode
+// Bidirectional Algorithm. Lines are byte ranges into the text that don't
+// cross a paragraph, they're reordered on their own so wrapping keeps the
+// logical order of the lines.
+pub fn line_runs(text: &str, lines: &[Range<usize>]) -> Vec<LineRuns>
+{
+    let info = BidiInfo::new(text, None);
+    lines
+        .iter()
+        .map(|line| {
+            let para = info
+                .paragraphs
+                .iter()
+                .find(|para| para.range.end > line.start);
+            match para {
+                Some(para) if !line.is_empty() => {
+                    let (levels, runs) = info.visual_runs(para, line.clone());
+                    let runs = runs
+                        .into_iter()
+                        .map(|run| {
+                            let rtl = levels[run.start].is_rtl();
+                            (run, rtl)
+                        })
+                        .collect();
+                    LineRuns {
+                        runs,
+                        rtl: para.level.is_rtl(),
+                    }
+                }
+                Some(para) => LineRuns {
+                    runs: Vec::new(),
+                    rtl: para.level.is_rtl(),
+                },
+                None => LineRuns {
+                    runs: Vec::new(),
+                    rtl: false,
+                },
+            }
+        })
+        .collect()
+}
+
+// The characters of a right to left run in visual order, with the byte
+// offset of each. Combining marks stay after the character they belong to,
+// and brackets are mirrored.
+pub fn rtl_chars(text: &str) -> Vec<(usize, char)>
+{
+    let chars: Vec<_> = text.char_indices().collect();
+    let mut visual = Vec::with_capacity(chars.len());
+    let mut end = chars.len();
+    while end > 0 {
+        let mut start = end - 1;
+        while start > 0 && bidi_class(chars[start].1) == BidiClass::NSM {
+            start -= 1;
+        }
+        for (i, c) in &chars[start..end] {
+            visual.push((*i, mirror(*c)));
+        }
+        end = start;
+    }
+    visual
+}
+
+// The mirrored form of the common paired brackets.
+fn mirror(c: char) -> char
+{
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        c => c,
+    }
+}
@@ -0,0 +1,90 @@
+use gif::{DisposalMethod, Frame};
+
+/// Draws the (possibly partial) frames of a gif onto a canvas the size of the
+/// whole image, the way a viewer would. Every frame is disposed of according
+/// to its `DisposalMethod` before the next one is drawn, so each call to
+/// `composite` yields the complete picture shown at that point.
+///
+/// Frames have to be decoded with `ColorOutput::RGBA`, pixels never covered
+/// by any frame are left fully transparent.
+pub struct Compositor
+{
+    width: usize,
+    height: usize,
+    canvas: Vec<u8>,
+    // disposal of the last frame, applied before drawing the next one
+    dispose: DisposalMethod,
+    // left, top, width, height of the last frame, clipped to the canvas
+    rect: (usize, usize, usize, usize),
+    // the canvas as it was before the last frame, kept for `Previous`
+    saved: Option<Vec<u8>>,
+}
+
+impl Compositor
+{
+    pub fn new(width: u16, height: u16) -> Self
+    {
+        let (width, height) = (width as usize, height as usize);
+        Compositor {
+            width,
+            height,
+            canvas: vec![0; width * height * 4],
+            dispose: DisposalMethod::Keep,
+            rect: (0, 0, 0, 0),
+            saved: None,
+        }
+    }
+
+    /// Draw the next frame and return the rgba canvas.
+    pub fn composite(&mut self, frame: &Frame) -> &[u8]
+    {
+        self.dispose();
+
+        let left = (frame.left as usize).min(self.width);
+        let top = (frame.top as usize).min(self.height);
+        let width = (frame.width as usize).min(self.width - left);
+        let height = (frame.height as usize).min(self.height - top);
+
+        if let DisposalMethod::Previous = frame.dispose {
+            self.saved = Some(self.canvas.clone());
+        }
+
+        // an empty frame has no rows to draw, but is disposed of all the same
+        let row_len = (frame.width as usize * 4).max(1);
+        for (y, row) in frame.buffer.chunks(row_len).take(height).enumerate() {
+            let start = ((top + y) * self.width + left) * 4;
+            let line = &mut self.canvas[start..start + width * 4];
+            for (old, new) in line.chunks_exact_mut(4).zip(row.chunks_exact(4)) {
+                // transparent pixels let the canvas underneath show through
+                if new[3] != 0 {
+                    old.copy_from_slice(new);
+                }
+            }
+        }
+
+        self.dispose = frame.dispose;
+        self.rect = (left, top, width, height);
+        &self.canvas
+    }
+
+    fn dispose(&mut self)
+    {
+        match self.dispose {
+            DisposalMethod::Background => {
+                // viewers clear to transparent rather than the actual
+                // background colour, and so do we
+                let (left, top, width, height) = self.rect;
+                for y in top..top + height {
+                    let start = (y * self.width + left) * 4;
+                    self.canvas[start..start + width * 4].fill(0);
+                }
+            }
+            DisposalMethod::Previous => {
+                if let Some(saved) = self.saved.take() {
+                    self.canvas = saved;
+                }
+            }
+            DisposalMethod::Keep | DisposalMethod::Any => {}
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use png::{ColorType, Decoder, Transformations};
+use ttf_parser::{Face, GlyphId, RasterImageFormat};
+
+/// The colour bitmaps (CBDT or sbix PNG strikes) of an emoji font. fontdue
+/// only draws outlines, so these glyphs are drawn from here instead.
+#[derive(Clone)]
+pub struct ColourGlyphs
+{
+    // the biggest strike of every glyph that has one, by glyph id
+    strikes: HashMap<u16, Strike>,
+}
+
+// The decoded PNG strike of a glyph, and where it goes relative to the
+// glyph's origin at `pixels_per_em`, with y pointing up.
+#[derive(Clone)]
+struct Strike
+{
+    x: i16,
+    y: i16,
+    height: u16,
+    pixels_per_em: u16,
+    pixels: Vec<[u8; 4]>,
+    width: usize,
+}
+
+// An rgba image of a glyph, and where it goes relative to the glyph's origin
+// on the baseline, with y pointing down.
+pub struct ColourGlyph
+{
+    pub left: f32,
+    pub top: f32,
+    pub width: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl ColourGlyphs
+{
+    // None for fonts without any colour bitmaps. The font is parsed once,
+    // when it's registered, and only the decoded PNG strikes are kept.
+    // They're scaled to the caption's size anyway, so every glyph keeps its
+    // biggest one.
+    pub fn new(bytes: &[u8]) -> Option<Self>
+    {
+        let face = Face::from_slice(bytes, 0).ok()?;
+        let tables = face.tables();
+        if tables.cbdt.is_none() && tables.sbix.is_none() {
+            return None;
+        }
+        let strikes = (0..face.number_of_glyphs())
+            .filter_map(|id| {
+                let image = face.glyph_raster_image(GlyphId(id), u16::MAX)?;
+                // PNG is the only format there is
+                let RasterImageFormat::PNG = image.format;
+                // a strike without a size can't be scaled to anything
+                if image.pixels_per_em == 0 {
+                    return None;
+                }
+                let (pixels, width, _) = decode_png(image.data)?;
+                if pixels.is_empty() {
+                    return None;
+                }
+                let strike = Strike {
+                    x: image.x,
+                    y: image.y,
+                    height: image.height,
+                    pixels_per_em: image.pixels_per_em,
+                    pixels,
+                    width,
+                };
+                Some((id, strike))
+            })
+            .collect();
+        Some(ColourGlyphs { strikes })
+    }
+
+    // The bitmap of a glyph scaled to a font size of `px`, if the font has
+    // one.
+    pub fn rasterize(&self, id: GlyphId, px: f32) -> Option<ColourGlyph>
+    {
+        let strike = self.strikes.get(&id.0)?;
+        let (src_w, src_h) = (strike.width, strike.pixels.len() / strike.width);
+        let scale = px / strike.pixels_per_em as f32;
+        let width = ((src_w as f32 * scale).round() as usize).max(1);
+        let height = ((src_h as f32 * scale).round() as usize).max(1);
+
+        Some(ColourGlyph {
+            left: strike.x as f32 * scale,
+            // the offset is to the bottom of the image, with y pointing up
+            top: -(strike.y as f32 + strike.height as f32) * scale,
+            width,
+            pixels: resize(&strike.pixels, (src_w, src_h), (width, height)),
+        })
+    }
+}
+
+// Decode a PNG strike into rgba pixels.
+fn decode_png(data: &[u8]) -> Option<(Vec<[u8; 4]>, usize, usize)>
+{
+    let mut decoder = Decoder::new(data);
+    decoder.set_transformations(
+        Transformations::EXPAND | Transformations::STRIP_16,
+    );
+    let mut reader = decoder.read_info().ok()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).ok()?;
+    let (w, h) = (info.width as usize, info.height as usize);
+
+    let pixels = match info.color_type {
+        ColorType::Rgba => buf
+            .chunks_exact(4)
+            .map(|x| [x[0], x[1], x[2], x[3]])
+            .collect(),
+        ColorType::Rgb => buf
+            .chunks_exact(3)
+            .map(|x| [x[0], x[1], x[2], 255])
+            .collect(),
+        ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .map(|x| [x[0], x[0], x[0], x[1]])
+            .collect(),
+        ColorType::Grayscale => buf.iter().map(|x| [*x, *x, *x, 255]).collect(),
+        // expanded away by the decoder
+        ColorType::Indexed => return None,
+    };
+    Some((pixels, w, h))
+}
+
+// Scale an image by averaging the source pixels every new pixel covers, which
+// is plenty for shrinking emoji strikes down to caption sizes.
+fn resize(
+    pixels: &[[u8; 4]],
+    (src_w, src_h): (usize, usize),
+    (w, h): (usize, usize),
+) -> Vec<[u8; 4]>
+{
+    let mut out = Vec::with_capacity(w * h);
+    for y in 0..h {
+        let (y0, y1) =
+            (y * src_h / h, ((y + 1) * src_h / h).max(y * src_h / h + 1));
+        for x in 0..w {
+            let (x0, x1) =
+                (x * src_w / w, ((x + 1) * src_w / w).max(x * src_w / w + 1));
+            let mut sum = [0u32; 4];
+            let mut n = 0;
+            for row in pixels.chunks_exact(src_w).take(y1.min(src_h)).skip(y0) {
+                for pixel in &row[x0.min(src_w)..x1.min(src_w)] {
+                    // weigh colours by alpha, so transparent pixels don't
+                    // darken the edges
+                    let alpha = pixel[3] as u32;
+                    for i in 0..3 {
+                        sum[i] += pixel[i] as u32 * alpha;
+                    }
+                    sum[3] += alpha;
+                    n += 1;
+                }
+            }
+            out.push(match sum[3] {
+                0 => [0; 4],
+                alpha => [
+                    (sum[0] / alpha) as u8,
+                    (sum[1] / alpha) as u8,
+                    (sum[2] / alpha) as u8,
+                    (alpha / n.max(1)) as u8,
+                ],
+            });
+        }
+    }
+    out
+}
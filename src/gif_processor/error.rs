@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+
+use gif::{DecodingError, EncodingError};
+
+use super::Overflow;
+
+/// Everything that can go wrong while captioning a gif.
+#[derive(Debug)]
+pub enum CaptionError
+{
+    // the input could not be read as a gif
+    Decode(DecodingError),
+    // the output could not be written
+    Encode(EncodingError),
+    // the input is a valid gif, but not one we know how to handle
+    Unsupported(String),
+    // the caption can't be placed with the given sizes or font
+    Layout(String),
+    // the caption doesn't fit and `OverflowPolicy::Fail` was asked for
+    Overflow(Overflow),
+}
+
+impl fmt::Display for CaptionError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            Self::Decode(err) => write!(f, "Could not decode gif: {}", err),
+            Self::Encode(err) => write!(f, "Could not encode gif: {}", err),
+            Self::Unsupported(msg) => write!(f, "Unsupported gif: {}", msg),
+            Self::Layout(msg) => write!(f, "Could not fit caption: {}", msg),
+            Self::Overflow(overflow) => write!(f, "{}", overflow),
+        }
+    }
+}
+
+impl Error for CaptionError
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)>
+    {
+        match self {
+            Self::Decode(err) => Some(err),
+            Self::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecodingError> for CaptionError
+{
+    fn from(err: DecodingError) -> Self
+    {
+        Self::Decode(err)
+    }
+}
+
+impl From<EncodingError> for CaptionError
+{
+    fn from(err: EncodingError) -> Self
+    {
+        Self::Encode(err)
+    }
+}
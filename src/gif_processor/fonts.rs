@@ -0,0 +1,169 @@
+use std::fmt;
+
+use fontdue::{Font, FontSettings};
+
+use super::emoji::ColourGlyphs;
+use super::CaptionError;
+
+// name the compiled-in font is registered under
+#[cfg(feature = "default-font")]
+pub const DEFAULT_FONT: &str = "Fjalla One";
+
+/// Fonts captions can be drawn with, looked up by name. Every font is parsed
+/// once when it's registered, and shared by all the captions using it.
+///
+/// With the `default-font` feature the registry starts out with Fjalla One,
+/// otherwise at least one font has to be registered before captioning.
+#[derive(Clone)]
+pub struct FontRegistry
+{
+    fonts: Vec<(String, Font)>,
+    // colour bitmaps of the fonts that have them, by name
+    colour: Vec<(String, ColourGlyphs)>,
+    // the raw fonts for the shaper, by name
+    #[cfg(feature = "shaping")]
+    data: Vec<(String, Vec<u8>)>,
+}
+
+impl FontRegistry
+{
+    /// A registry without any fonts, not even the default one.
+    pub fn empty() -> Self
+    {
+        FontRegistry {
+            fonts: Vec::new(),
+            colour: Vec::new(),
+            #[cfg(feature = "shaping")]
+            data: Vec::new(),
+        }
+    }
+
+    /// Parse TTF or OTF bytes and add them under `name`, replacing any font
+    /// that already had that name. Colour emoji fonts with CBDT or sbix
+    /// bitmaps are drawn in colour.
+    pub fn register<S: Into<String>>(
+        &mut self,
+        name: S,
+        bytes: &[u8],
+    ) -> Result<(), CaptionError>
+    {
+        let name = name.into();
+        let font =
+            Font::from_bytes(bytes, FontSettings::default()).map_err(|err| {
+                CaptionError::Layout(format!("font {}: {}", name, err))
+            })?;
+        self.colour.retain(|(n, _)| *n != name);
+        if let Some(colour) = ColourGlyphs::new(bytes) {
+            self.colour.push((name.clone(), colour));
+        }
+        #[cfg(feature = "shaping")]
+        {
+            self.data.retain(|(n, _)| *n != name);
+            self.data.push((name.clone(), bytes.to_vec()));
+        }
+        match self.fonts.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, old)) => *old = font,
+            None => self.fonts.push((name, font)),
+        }
+        Ok(())
+    }
+
+    /// The font called `name`, or the first one registered if no name is
+    /// given.
+    pub fn get(&self, name: Option<&str>) -> Result<&Font, CaptionError>
+    {
+        let font = match name {
+            Some(name) => self.fonts.iter().find(|(n, _)| n == name),
+            None => self.fonts.first(),
+        };
+        font.map(|(_, font)| font).ok_or_else(|| {
+            CaptionError::Layout(match name {
+                Some(name) => format!("no font called {}", name),
+                None => "no fonts registered".into(),
+            })
+        })
+    }
+
+    /// The font called `name` followed by its `fallback` fonts, which are
+    /// tried in order for characters it has no glyph for. Without any
+    /// fallback fonts every other registered font is one, in the order they
+    /// were registered.
+    pub fn chain(
+        &self,
+        name: Option<&str>,
+        fallback: &[String],
+    ) -> Result<Vec<&Font>, CaptionError>
+    {
+        let first = self.get(name)?;
+        let mut chain = vec![first];
+        if fallback.is_empty() {
+            chain.extend(
+                self.fonts
+                    .iter()
+                    .map(|(_, font)| font)
+                    .filter(|font| !std::ptr::eq(*font, first)),
+            );
+        } else {
+            for name in fallback {
+                chain.push(self.get(Some(name))?);
+            }
+        }
+        Ok(chain)
+    }
+
+    // The colour bitmaps of a font from this registry, if it has any.
+    pub fn colour_glyphs(&self, font: &Font) -> Option<&ColourGlyphs>
+    {
+        let (name, _) =
+            self.fonts.iter().find(|(_, f)| std::ptr::eq(f, font))?;
+        self.colour
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, colour)| colour)
+    }
+
+    // The bytes a font from this registry was registered with.
+    #[cfg(feature = "shaping")]
+    pub fn data(&self, font: &Font) -> Option<&[u8]>
+    {
+        let (name, _) =
+            self.fonts.iter().find(|(_, f)| std::ptr::eq(f, font))?;
+        self.data
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str>
+    {
+        self.fonts.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+impl Default for FontRegistry
+{
+    #[cfg(feature = "default-font")]
+    fn default() -> Self
+    {
+        let mut fonts = Self::empty();
+        let font = include_bytes!("../../fonts/FjallaOne-Regular.ttf");
+        fonts
+            .register(DEFAULT_FONT, font)
+            .expect("compiled-in font is valid");
+        fonts
+    }
+
+    #[cfg(not(feature = "default-font"))]
+    fn default() -> Self
+    {
+        Self::empty()
+    }
+}
+
+impl fmt::Debug for FontRegistry
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
@@ -0,0 +1,193 @@
+// Breaking words that are too wide for a line of their own. They're split at
+// the hyphenation points of a language's patterns, when the `hyphenate`
+// feature is on and a language is set, or else at any grapheme, and every
+// piece but the last gets a hyphen.
+
+#[cfg(feature = "hyphenate")]
+use hyphenation::{Hyphenator, Language, Load, Standard};
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::markup::{Span, Style};
+use super::CaptionError;
+
+// Ends a line without ending the paragraph. fontdue always breaks the line
+// there, the layout treats it like a wrap.
+const LINE_SEPARATOR: char = '\u{2028}';
+const HYPHEN: char = '-';
+
+// The hyphenation patterns of a language, if any.
+pub struct Patterns
+{
+    #[cfg(feature = "hyphenate")]
+    dictionary: Option<Standard>,
+}
+
+impl Patterns
+{
+    // Load the patterns for a language code like "en-us". Without the
+    // `hyphenate` feature there are none.
+    pub fn new(language: Option<&str>) -> Result<Self, CaptionError>
+    {
+        #[cfg(feature = "hyphenate")]
+        {
+            let dictionary = match language {
+                Some(code) => {
+                    let language =
+                        Language::try_from_code(code).ok_or_else(|| {
+                            CaptionError::Layout(format!(
+                                "no hyphenation patterns for {}",
+                                code
+                            ))
+                        })?;
+                    let dictionary = Standard::from_embedded(language)
+                        .map_err(|err| CaptionError::Layout(err.to_string()))?;
+                    Some(dictionary)
+                }
+                None => None,
+            };
+            Ok(Patterns { dictionary })
+        }
+        #[cfg(not(feature = "hyphenate"))]
+        {
+            let _ = language;
+            Ok(Patterns {})
+        }
+    }
+
+    // Byte offsets into the word where it can be hyphenated. Punctuation
+    // around the word is left out, the patterns only know letters.
+    fn points(&self, word: &str) -> Vec<usize>
+    {
+        #[cfg(feature = "hyphenate")]
+        if let Some(dictionary) = &self.dictionary {
+            let start = word.find(char::is_alphabetic).unwrap_or(0);
+            let letters =
+                word[start..].trim_end_matches(|c: char| !c.is_alphabetic());
+            return dictionary
+                .hyphenate(letters)
+                .breaks
+                .into_iter()
+                .map(|b| start + b)
+                .collect();
+        }
+        let _ = word;
+        Vec::new()
+    }
+}
+
+// The words of the text and their byte offsets. Lines can already be broken
+// after a hyphen, so it ends a word too.
+pub fn words(text: &str) -> Vec<(usize, &str)>
+{
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push((start, &text[start..i]));
+            }
+            continue;
+        }
+        let word_start = *start.get_or_insert(i);
+        if c == HYPHEN {
+            words.push((word_start, &text[word_start..i + 1]));
+            start = None;
+        }
+    }
+    if let Some(start) = start {
+        words.push((start, &text[start..]));
+    }
+    words
+}
+
+// Break every word of the spans that's wider than `max_width` into pieces
+// that fit, as long as they can, each ending with a hyphen and a line
+// separator. `advance` is how far a grapheme cluster of a style moves the
+// pen. A grapheme too wide for any line gets one of its own, no text is
+// dropped.
+pub fn break_words<F>(
+    spans: &[Span],
+    max_width: f32,
+    patterns: &Patterns,
+    advance: F,
+) -> Vec<Span>
+where
+    F: Fn(&str, &Style) -> f32,
+{
+    let text: String = spans.iter().map(|span| span.text.as_str()).collect();
+    let styles: Vec<Style> = spans
+        .iter()
+        .flat_map(|span| vec![span.style; span.text.len()])
+        .collect();
+    let width = |start: usize, end: usize| -> f32 {
+        text[start..end]
+            .grapheme_indices(true)
+            .map(|(i, g)| advance(g, &styles[start + i]))
+            .sum()
+    };
+    let hyphen = HYPHEN.to_string();
+
+    let mut breaks = Vec::new();
+    for (start, word) in words(&text) {
+        let end = start + word.len();
+        if width(start, end) <= max_width {
+            continue;
+        }
+        let graphemes: Vec<usize> = word
+            .grapheme_indices(true)
+            .skip(1)
+            .map(|(b, _)| start + b)
+            .collect();
+        // a mark stays with the letter it's on
+        let points: Vec<usize> = patterns
+            .points(word)
+            .into_iter()
+            .map(|b| start + b)
+            .filter(|b| graphemes.contains(b))
+            .collect();
+
+        let mut line = start;
+        while width(line, end) > max_width {
+            let fits = |b: &&usize| {
+                **b > line
+                    && width(line, **b) + advance(&hyphen, &styles[**b - 1])
+                        <= max_width
+            };
+            let b = points
+                .iter()
+                .rev()
+                .find(fits)
+                .or_else(|| graphemes.iter().rev().find(fits))
+                .or_else(|| graphemes.iter().find(|b| **b > line));
+            match b {
+                Some(b) => {
+                    breaks.push(*b);
+                    line = *b;
+                }
+                None => break,
+            }
+        }
+    }
+    if breaks.is_empty() {
+        return spans.to_vec();
+    }
+
+    let mut broken: Vec<Span> = Vec::new();
+    let mut push = |c: char, style: Style| match broken.last_mut() {
+        Some(last) if last.style == style => last.text.push(c),
+        _ => broken.push(Span {
+            text: c.into(),
+            style,
+        }),
+    };
+    let mut breaks = breaks.into_iter().peekable();
+    for (i, c) in text.char_indices() {
+        if breaks.peek() == Some(&i) {
+            breaks.next();
+            push(HYPHEN, styles[i - 1]);
+            push(LINE_SEPARATOR, styles[i - 1]);
+        }
+        push(c, styles[i]);
+    }
+    broken
+}
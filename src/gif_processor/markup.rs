@@ -0,0 +1,169 @@
+// Inline markup for captions: `*bold*`, `_italic_`, `{red}colours{/}`,
+// `{#ff8800}hex colours{/}` and `{1.5x}sizes{/}`. A backslash takes the next
+// character literally, and braces that aren't markup stay text.
+
+// nested sizes multiply, but stay within this much of the caption's font size
+const MIN_SCALE: f32 = 0.1;
+const MAX_SCALE: f32 = 10.0;
+
+// how a span of text is drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style
+{
+    pub bold: bool,
+    pub italic: bool,
+    // None draws with the caption's text colour
+    pub colour: Option<[u8; 3]>,
+    // relative to the caption's font size
+    pub scale: f32,
+}
+
+impl Default for Style
+{
+    fn default() -> Self
+    {
+        Style {
+            bold: false,
+            italic: false,
+            colour: None,
+            scale: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span
+{
+    pub text: String,
+    pub style: Style,
+}
+
+// The whole text as one span without any styling.
+pub fn plain(text: &str) -> Vec<Span>
+{
+    vec![Span {
+        text: text.into(),
+        style: Style::default(),
+    }]
+}
+
+// Split marked up text into spans. Markers that are never closed style the
+// rest of the text, and `{/}` ends the innermost brace.
+pub fn parse(text: &str) -> Vec<Span>
+{
+    let mut spans = Vec::new();
+    let mut current = String::new();
+    let mut style = Style::default();
+    // styles to go back to at each `{/}`
+    let mut stack = Vec::new();
+
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        let next = match c {
+            '\\' => {
+                if let Some(c) = rest.chars().next() {
+                    current.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+                continue;
+            }
+            '*' => Style {
+                bold: !style.bold,
+                ..style
+            },
+            '_' => Style {
+                italic: !style.italic,
+                ..style
+            },
+            '{' => {
+                let tag = rest.find('}').map(|end| (&rest[..end], end));
+                let next = match tag {
+                    Some(("/", _)) => Some(stack.pop().unwrap_or(style)),
+                    Some((tag, _)) => {
+                        let next = apply_tag(tag, style);
+                        if next.is_some() {
+                            stack.push(style);
+                        }
+                        next
+                    }
+                    None => None,
+                };
+                match (next, tag) {
+                    (Some(next), Some((_, end))) => {
+                        rest = &rest[end + 1..];
+                        next
+                    }
+                    _ => {
+                        current.push(c);
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                current.push(c);
+                continue;
+            }
+        };
+        if next != style && !current.is_empty() {
+            spans.push(Span {
+                text: std::mem::take(&mut current),
+                style,
+            });
+        }
+        style = next;
+    }
+    if !current.is_empty() || spans.is_empty() {
+        spans.push(Span {
+            text: current,
+            style,
+        });
+    }
+    spans
+}
+
+// The style inside a `{tag}`, None if it isn't one.
+fn apply_tag(tag: &str, style: Style) -> Option<Style>
+{
+    if let Some(scale) = tag.strip_suffix('x') {
+        let scale: f32 = scale.parse().ok()?;
+        if !scale.is_normal() || scale < 0.0 {
+            return None;
+        }
+        return Some(Style {
+            scale: (style.scale * scale).clamp(MIN_SCALE, MAX_SCALE),
+            ..style
+        });
+    }
+    Some(Style {
+        colour: Some(colour(tag)?),
+        ..style
+    })
+}
+
+// "#rrggbb" or one of a few colour names
+fn colour(name: &str) -> Option<[u8; 3]>
+{
+    if let Some(hex) = name.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some([channel(0)?, channel(2)?, channel(4)?]);
+    }
+    Some(match name.to_lowercase().as_str() {
+        "black" => [0, 0, 0],
+        "white" => [255, 255, 255],
+        "grey" | "gray" => [128, 128, 128],
+        "red" => [255, 0, 0],
+        "green" => [0, 160, 0],
+        "blue" => [0, 0, 255],
+        "yellow" => [255, 255, 0],
+        "orange" => [255, 140, 0],
+        "purple" => [128, 0, 128],
+        "pink" => [255, 105, 180],
+        "cyan" => [0, 255, 255],
+        "magenta" => [255, 0, 255],
+        _ => return None,
+    })
+}
@@ -0,0 +1,432 @@
+use std::sync::Arc;
+
+use super::{ColourCompression, FontRegistry};
+
+// default height of the caption bar relative to the gif
+const SCALE: f32 = 0.3;
+// WCAG's minimum contrast for normal text
+const MIN_CONTRAST: f32 = 4.5;
+
+/// `Bar` adds caption bars to the gif, `Overlay` writes the caption straight
+/// onto the frames without changing the size of the gif.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaptionMode
+{
+    #[default]
+    Bar,
+    Overlay,
+}
+
+/// Where each line of a caption goes between the sides of its bar. `Justify`
+/// spreads wrapped lines out to both sides, the last line of a paragraph is
+/// set to the side the paragraph starts from.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TextAlign
+{
+    Left,
+    #[default]
+    Center,
+    Right,
+    Justify,
+}
+
+/// Where the text goes between the top and bottom of its bar.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VerticalAlign
+{
+    Top,
+    #[default]
+    Middle,
+    Bottom,
+}
+
+/// Where lines may wrap: between words, or after any character.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Wrap
+{
+    #[default]
+    Word,
+    Letter,
+}
+
+/// Space in pixels kept free on each side of the text.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Padding
+{
+    pub top: u16,
+    pub right: u16,
+    pub bottom: u16,
+    pub left: u16,
+}
+
+impl Padding
+{
+    pub fn all(px: u16) -> Self
+    {
+        Padding {
+            top: px,
+            right: px,
+            bottom: px,
+            left: px,
+        }
+    }
+}
+
+/// A copy of the text, outline included, drawn `offset` pixels right and
+/// down behind it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shadow
+{
+    pub offset: (i16, i16),
+    pub colour: [u8; 3],
+}
+
+/// What to do with a caption that doesn't fit into its bar: draw what fits,
+/// make the bar tall enough for the text, or fail with
+/// `CaptionError::Overflow`. Growing fails too if no height would do.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy
+{
+    #[default]
+    Clip,
+    Grow,
+    Fail,
+}
+
+/// How tall the caption bars are: a fraction of the gif's height, a fixed
+/// number of pixels, or just tall enough for the wrapped text with `padding`
+/// pixels around it. Fitting the bar to the text needs a font size.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BarHeight
+{
+    Scale(f32),
+    Pixels(u16),
+    Fit
+    {
+        padding: u16,
+    },
+}
+
+impl Default for BarHeight
+{
+    fn default() -> Self
+    {
+        BarHeight::Scale(SCALE)
+    }
+}
+
+/// Where the caption bars go. `Both` puts `text` above the gif and
+/// `bottom_text` below it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaptionPosition
+{
+    #[default]
+    Top,
+    Bottom,
+    Both,
+}
+
+/// How a caption is drawn onto a gif. Every field has a sensible default, so
+/// start with `CaptionOptions::new` and only change what's needed:
+///
+/// ```ignore
+/// let options = CaptionOptions::new("me when the build is green")
+///     .compression(ColourCompression::Wu(128))
+///     .font_size(24.0);
+/// let captioned = caption(&bytes, &options)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct CaptionOptions
+{
+    pub text: String,
+    // only drawn with `CaptionPosition::Both`
+    pub bottom_text: String,
+    pub position: CaptionPosition,
+    pub mode: CaptionMode,
+    pub compression: ColourCompression,
+    // height of each caption bar, for overlays the height of the area the
+    // text is fitted into
+    pub bar_height: BarHeight,
+    // limits for the bar height in px, whatever it's derived from
+    pub min_bar_height: Option<u16>,
+    pub max_bar_height: Option<u16>,
+    // font size in px, the biggest size the text fits into the bar at if None
+    pub font_size: Option<f32>,
+    // fonts to draw with, and which of them to use. Without a name the
+    // first registered font is used
+    pub fonts: Arc<FontRegistry>,
+    pub font: Option<String>,
+    // fonts for the characters `font` has no glyph for, see
+    // `FontRegistry::chain`
+    pub fallback_fonts: Vec<String>,
+    // parse `*bold*`, `_italic_` and `{red}colour{/}` markup in the text.
+    // Bold and italic text without a font for it is faked
+    pub markup: bool,
+    pub bold_font: Option<String>,
+    pub italic_font: Option<String>,
+    pub overflow: OverflowPolicy,
+    pub align: TextAlign,
+    pub vertical_align: VerticalAlign,
+    pub wrap: Wrap,
+    // None keeps a margin that grows with the bar, or the padding of
+    // `BarHeight::Fit`
+    pub padding: Option<Padding>,
+    // distance between baselines relative to the font's, and extra space
+    // after every character in px
+    pub line_height: f32,
+    pub letter_spacing: f32,
+    // words too wide for a line are hyphenated with the patterns for this
+    // language, like "en-us", with the `hyphenate` feature. Otherwise, or
+    // where the patterns don't help, they're broken after any character
+    pub language: Option<String>,
+    // None picks a colour from the gif's palette that stands out against the
+    // bar, or the frame behind overlays, with at least `min_contrast`
+    pub text_colour: Option<[u8; 3]>,
+    pub background_colour: [u8; 3],
+    // WCAG contrast ratio, from 1 to 21
+    pub min_contrast: f32,
+    // outline around the text so it's readable on any background, in px.
+    // None draws a 2px outline for overlays and none for bars
+    pub outline_width: Option<u16>,
+    pub outline_colour: [u8; 3],
+    pub shadow: Option<Shadow>,
+    // anti-alias the text with a few greys when there's no colour
+    // compression, which already draws smooth text
+    pub smooth_font: bool,
+}
+
+impl Default for CaptionOptions
+{
+    fn default() -> Self
+    {
+        CaptionOptions {
+            text: String::new(),
+            bottom_text: String::new(),
+            position: CaptionPosition::default(),
+            mode: CaptionMode::default(),
+            compression: ColourCompression::default(),
+            bar_height: BarHeight::default(),
+            min_bar_height: None,
+            max_bar_height: None,
+            font_size: None,
+            fonts: Arc::new(FontRegistry::default()),
+            font: None,
+            fallback_fonts: Vec::new(),
+            markup: false,
+            bold_font: None,
+            italic_font: None,
+            overflow: OverflowPolicy::default(),
+            align: TextAlign::default(),
+            vertical_align: VerticalAlign::default(),
+            wrap: Wrap::default(),
+            padding: None,
+            line_height: 1.0,
+            letter_spacing: 0.0,
+            language: None,
+            text_colour: None,
+            background_colour: [255; 3],
+            min_contrast: MIN_CONTRAST,
+            outline_width: None,
+            outline_colour: [0; 3],
+            shadow: None,
+            smooth_font: false,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl CaptionOptions
+{
+    pub fn new<S: Into<String>>(text: S) -> Self
+    {
+        CaptionOptions {
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self
+    {
+        self.text = text.into();
+        self
+    }
+
+    pub fn bottom_text<S: Into<String>>(mut self, text: S) -> Self
+    {
+        self.bottom_text = text.into();
+        self
+    }
+
+    pub fn position(mut self, position: CaptionPosition) -> Self
+    {
+        self.position = position;
+        self
+    }
+
+    pub fn mode(mut self, mode: CaptionMode) -> Self
+    {
+        self.mode = mode;
+        self
+    }
+
+    pub fn compression(mut self, compression: ColourCompression) -> Self
+    {
+        self.compression = compression;
+        self
+    }
+
+    pub fn scale(mut self, scale: f32) -> Self
+    {
+        self.bar_height = BarHeight::Scale(scale);
+        self
+    }
+
+    pub fn bar_height(mut self, bar_height: BarHeight) -> Self
+    {
+        self.bar_height = bar_height;
+        self
+    }
+
+    pub fn clamp_bar_height(mut self, min: Option<u16>, max: Option<u16>)
+        -> Self
+    {
+        self.min_bar_height = min;
+        self.max_bar_height = max;
+        self
+    }
+
+    pub fn font_size(mut self, font_size: f32) -> Self
+    {
+        self.font_size = Some(font_size);
+        self
+    }
+
+    pub fn fonts(mut self, fonts: Arc<FontRegistry>) -> Self
+    {
+        self.fonts = fonts;
+        self
+    }
+
+    pub fn font<S: Into<String>>(mut self, name: S) -> Self
+    {
+        self.font = Some(name.into());
+        self
+    }
+
+    pub fn fallback_fonts<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fallback_fonts = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn markup(mut self, markup: bool) -> Self
+    {
+        self.markup = markup;
+        self
+    }
+
+    pub fn bold_font<S: Into<String>>(mut self, name: S) -> Self
+    {
+        self.bold_font = Some(name.into());
+        self
+    }
+
+    pub fn italic_font<S: Into<String>>(mut self, name: S) -> Self
+    {
+        self.italic_font = Some(name.into());
+        self
+    }
+
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self
+    {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn align(mut self, align: TextAlign) -> Self
+    {
+        self.align = align;
+        self
+    }
+
+    pub fn vertical_align(mut self, align: VerticalAlign) -> Self
+    {
+        self.vertical_align = align;
+        self
+    }
+
+    pub fn wrap(mut self, wrap: Wrap) -> Self
+    {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn padding(mut self, padding: Padding) -> Self
+    {
+        self.padding = Some(padding);
+        self
+    }
+
+    pub fn line_height(mut self, line_height: f32) -> Self
+    {
+        self.line_height = line_height;
+        self
+    }
+
+    pub fn letter_spacing(mut self, px: f32) -> Self
+    {
+        self.letter_spacing = px;
+        self
+    }
+
+    pub fn language<S: Into<String>>(mut self, code: S) -> Self
+    {
+        self.language = Some(code.into());
+        self
+    }
+
+    pub fn text_colour(mut self, colour: [u8; 3]) -> Self
+    {
+        self.text_colour = Some(colour);
+        self
+    }
+
+    pub fn background_colour(mut self, colour: [u8; 3]) -> Self
+    {
+        self.background_colour = colour;
+        self
+    }
+
+    pub fn min_contrast(mut self, ratio: f32) -> Self
+    {
+        self.min_contrast = ratio;
+        self
+    }
+
+    pub fn outline(mut self, width: u16, colour: [u8; 3]) -> Self
+    {
+        self.outline_width = Some(width);
+        self.outline_colour = colour;
+        self
+    }
+
+    pub fn shadow(mut self, offset: (i16, i16), colour: [u8; 3]) -> Self
+    {
+        self.shadow = Some(Shadow { offset, colour });
+        self
+    }
+
+    pub fn smooth_font(mut self, smooth: bool) -> Self
+    {
+        self.smooth_font = smooth;
+        self
+    }
+}
@@ -0,0 +1,98 @@
+use fontdue::Font;
+#[cfg(feature = "shaping")]
+pub use rustybuzz::Face;
+#[cfg(feature = "shaping")]
+use rustybuzz::{Direction, UnicodeBuffer};
+
+use super::bidi;
+
+// A glyph out of the shaper, scaled to px: which glyph it is, the byte offset
+// of the characters it was made from, how far it moves the pen, and how far
+// it's moved from the pen, with y pointing up.
+pub struct Shaped
+{
+    pub glyph_index: u16,
+    pub cluster: usize,
+    pub advance: f32,
+    pub offset: (f32, f32),
+}
+
+// Parse a font for the shaper once, None if it can't be.
+#[cfg(feature = "shaping")]
+pub fn face(data: &[u8]) -> Option<Face<'_>>
+{
+    Face::from_slice(data, 0)
+}
+
+// Shape a run of text drawn with one font. Right to left runs come out in
+// visual order.
+#[cfg(feature = "shaping")]
+pub fn shape(face: &Face, text: &str, px: f32, rtl: bool) -> Vec<Shaped>
+{
+    let scale = px / face.units_per_em() as f32;
+
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.set_direction(match rtl {
+        true => Direction::RightToLeft,
+        false => Direction::LeftToRight,
+    });
+    let shaped = rustybuzz::shape(face, &[], buffer);
+
+    shaped
+        .glyph_infos()
+        .iter()
+        .zip(shaped.glyph_positions())
+        .map(|(info, pos)| Shaped {
+            glyph_index: info.glyph_id as u16,
+            cluster: info.cluster as usize,
+            advance: pos.x_advance as f32 * scale,
+            offset: (pos.x_offset as f32 * scale, pos.y_offset as f32 * scale),
+        })
+        .collect()
+}
+
+// Whether a character is default ignorable, like the joiners and variation
+// selectors inside emoji sequences. Those aren't drawn and don't move the pen
+// on their own, the shaper hides them too.
+pub fn ignorable(c: char) -> bool
+{
+    matches!(
+        c,
+        '\u{AD}'
+            | '\u{34F}'
+            | '\u{180B}'..='\u{180F}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{206F}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{E0000}'..='\u{E0FFF}'
+    )
+}
+
+// Characters one by one, without shaping, or for fonts the shaper can't
+// read. Right to left runs come out in visual order too. Ignorable
+// characters are left out.
+pub fn unshaped(font: &Font, text: &str, px: f32, rtl: bool) -> Vec<Shaped>
+{
+    let chars = match rtl {
+        true => bidi::rtl_chars(text),
+        false => text.char_indices().collect(),
+    };
+    chars
+        .into_iter()
+        .filter(|(_, c)| !ignorable(*c))
+        .map(|(cluster, c)| {
+            let glyph_index = font.lookup_glyph_index(c) as u16;
+            Shaped {
+                glyph_index,
+                cluster,
+                advance: font
+                    .metrics_indexed(glyph_index as usize, px)
+                    .advance_width,
+                offset: (0.0, 0.0),
+            }
+        })
+        .collect()
+}
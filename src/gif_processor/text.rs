@@ -0,0 +1,1035 @@
+use std::fmt;
+use std::ops::Range;
+
+use fontdue::layout::{
+    self, CoordinateSystem, HorizontalAlign, Layout, LayoutSettings, TextStyle,
+    WrapStyle,
+};
+use fontdue::Font;
+use ttf_parser::GlyphId;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::emoji::ColourGlyphs;
+use super::hyphenate::{self, Patterns};
+use super::markup::{self, Span, Style};
+use super::shaping::{self, Shaped};
+use super::{
+    bidi, BarHeight, CaptionError, CaptionMode, CaptionOptions, Padding,
+    TextAlign, VerticalAlign, Wrap,
+};
+
+// space kept free around the text, relative to the smaller side of the bar
+const MARGIN: f32 = 0.08;
+// smallest font size we try before giving up on fitting the text
+const MIN_PX: f32 = 4.0;
+// without a bold or italic font, text is thickened by a pixel for every this
+// many px of font size, and slanted by this much per pixel of height
+const FAUX_BOLD_PX: f32 = 24.0;
+const FAUX_SLANT: f32 = 0.2;
+// how often lines made too wide by letter spacing or shaping are wrapped again
+const REWRAP_RETRIES: usize = 4;
+// font size the text is measured at to guess the size it fits at
+const ESTIMATE_PX: f32 = 64.0;
+
+/// A caption that doesn't fit into its bar, and what would make it fit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overflow
+{
+    /// Lines the text wraps into, and how many of them fit into the bar.
+    pub lines: usize,
+    pub lines_fit: usize,
+    /// Font size the text was laid out at.
+    pub font_size: f32,
+    /// Biggest font size the text fits at, if any does.
+    pub suggested_font_size: Option<f32>,
+    /// Height of a bar that fits all of the text at `font_size`. Text that's
+    /// too wide for the bar won't fit at any height.
+    pub suggested_bar_height: Option<u16>,
+}
+
+impl fmt::Display for Overflow
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(
+            f,
+            "Caption does not fit: {} of {} lines fit at {}px",
+            self.lines_fit, self.lines, self.font_size
+        )?;
+        if let Some(px) = self.suggested_font_size {
+            write!(f, ", try a font size of {}px", px)?;
+        }
+        if let Some(h) = self.suggested_bar_height {
+            write!(f, ", or a bar {}px high", h)?;
+        }
+        Ok(())
+    }
+}
+
+// overlay text gets an outline unless asked not to
+const OVERLAY_OUTLINE: u16 = 2;
+
+// A drawn caption bar: the glyph coverage of every pixel, the rgba colour
+// glyphs and coloured text that go over it, and the coverage of the outline
+// and shadow behind the text. Layers that aren't drawn are left empty.
+#[derive(Default)]
+pub struct Piece
+{
+    pub coverage: Vec<u8>,
+    pub colour: Vec<[u8; 4]>,
+    pub outline: Vec<u8>,
+    pub shadow: Vec<u8>,
+    // colours of the text spans that have their own
+    pub span_colours: Vec<[u8; 3]>,
+}
+
+// A glyph ready to be drawn: the top left corner of its bitmap and its size,
+// which glyph of which font it is, the character and style it's from, and
+// which line it's on.
+struct Glyph
+{
+    x: f32,
+    y: f32,
+    width: usize,
+    height: usize,
+    font_index: usize,
+    glyph_index: u16,
+    px: f32,
+    parent: char,
+    style: Style,
+    line: usize,
+}
+
+// A line of the caption in visual order. Its glyphs are placed from 0 along
+// a baseline at 0, until they're arranged on the baseline fontdue put the
+// line on.
+struct Line
+{
+    glyphs: Vec<Glyph>,
+    width: f32,
+    baseline: f32,
+    // wrapped lines can be justified, the others end a paragraph
+    wrapped: bool,
+    // whether the paragraph runs right to left
+    rtl: bool,
+}
+
+// The fonts a caption is drawn with. The layout indexes into `fonts`, which
+// is the fallback chain followed by the bold and italic fonts, if any.
+struct StyledFonts<'a>
+{
+    fonts: Vec<&'a Font>,
+    // the fonts parsed for the shaper, None for those it can't read
+    #[cfg(feature = "shaping")]
+    faces: Vec<Option<shaping::Face<'a>>>,
+    chain: usize,
+    bold: Option<usize>,
+    italic: Option<usize>,
+    // what overlong words are hyphenated with
+    patterns: Patterns,
+}
+
+impl<'a> StyledFonts<'a>
+{
+    fn new(options: &'a CaptionOptions) -> Result<Self, CaptionError>
+    {
+        let mut fonts = options
+            .fonts
+            .chain(options.font.as_deref(), &options.fallback_fonts)?;
+        let chain = fonts.len();
+        let mut add = |name: &Option<String>| -> Result<_, CaptionError> {
+            match name {
+                Some(name) => {
+                    fonts.push(options.fonts.get(Some(name))?);
+                    Ok(Some(fonts.len() - 1))
+                }
+                None => Ok(None),
+            }
+        };
+        let bold = add(&options.bold_font)?;
+        let italic = add(&options.italic_font)?;
+        Ok(StyledFonts {
+            #[cfg(feature = "shaping")]
+            faces: fonts
+                .iter()
+                .map(|font| shaping::face(options.fonts.data(font)?))
+                .collect(),
+            fonts,
+            chain,
+            bold,
+            italic,
+            patterns: Patterns::new(options.language.as_deref())?,
+        })
+    }
+
+    // Indices of the fonts to try for a character of the style, best first.
+    // Bold italic text uses the bold font and gets slanted.
+    fn candidates(&self, style: &Style) -> impl Iterator<Item = usize>
+    {
+        let styled = match (style.bold, style.italic) {
+            (true, _) if self.bold.is_some() => self.bold,
+            (_, true) => self.italic,
+            _ => None,
+        };
+        styled.into_iter().chain(0..self.chain)
+    }
+
+    // How far a grapheme cluster of the style moves the pen, with `spacing`
+    // after every glyph that moves it. It's shaped the way the layout shapes
+    // it, so marks and joined up emoji sequences take up the room they're
+    // drawn in, and rounded up like fontdue rounds advances.
+    fn advance(&self, grapheme: &str, style: &Style, px: f32, spacing: f32)
+        -> f32
+    {
+        font_runs(self, grapheme, style)
+            .into_iter()
+            .flat_map(|(run, font_index)| {
+                shape(self, font_index, &grapheme[run], px * style.scale, false)
+            })
+            .filter(|glyph| glyph.advance > 0.0)
+            .map(|glyph| glyph.advance.ceil() + spacing)
+            .sum()
+    }
+
+    // Whether a glyph drawn with a font has to be made bold or italic
+    // by hand, because the font isn't.
+    fn faux(&self, font_index: usize, style: &Style) -> (bool, bool)
+    {
+        (
+            style.bold && self.bold != Some(font_index),
+            style.italic && self.italic != Some(font_index),
+        )
+    }
+}
+
+// The text split into styled spans if the options ask for markup.
+fn spans(text: &str, options: &CaptionOptions) -> Vec<Span>
+{
+    match options.markup {
+        true => markup::parse(text),
+        false => markup::plain(text),
+    }
+}
+
+// The caption bar, empty when there's no bar.
+pub fn render_bar(
+    w: u16,
+    height: u16,
+    text: Option<&str>,
+    options: &CaptionOptions,
+) -> Result<Piece, CaptionError>
+{
+    let text = match text {
+        Some(text) => text,
+        None => return Ok(Piece::default()),
+    };
+    let fonts = StyledFonts::new(options)?;
+    let spans = spans(text, options);
+    let margin = margin(w, height, options);
+    let space = area(w, height, options);
+    let px = font_size_for(&fonts, options, space, &spans, options.font_size)?;
+
+    let colour: Vec<_> = fonts
+        .fonts
+        .iter()
+        .map(|f| options.fonts.colour_glyphs(f))
+        .collect();
+
+    let mut piece =
+        make_piece((&fonts, &colour), options, (w, height), margin, px, &spans)?;
+    style_piece(&mut piece, w, options);
+    Ok(piece)
+}
+
+// Add the outline and shadow layers the options ask for.
+fn style_piece(piece: &mut Piece, w: u16, options: &CaptionOptions)
+{
+    let outline_width = match (options.outline_width, options.mode) {
+        (Some(width), _) => width,
+        (None, CaptionMode::Overlay) => OVERLAY_OUTLINE,
+        (None, CaptionMode::Bar) => 0,
+    };
+    // colour glyphs get an outline and shadow too
+    let mut shape = piece.coverage.clone();
+    for (s, glyph) in shape.iter_mut().zip(&piece.colour) {
+        *s = (*s).max(glyph[3]);
+    }
+
+    if outline_width > 0 {
+        piece.outline = dilate(&shape, w, outline_width);
+        for (s, o) in shape.iter_mut().zip(&piece.outline) {
+            *s = (*s).max(*o);
+        }
+    }
+    if let Some(shadow) = options.shadow {
+        piece.shadow = shift(&shape, w, shadow.offset);
+    }
+}
+
+// Spread coverage out by `radius` pixels in every direction, which gives the
+// coverage of an outline around the text.
+fn dilate(coverage: &[u8], w: u16, radius: u16) -> Vec<u8>
+{
+    let (w, r) = (w as usize, radius as isize);
+    let h = coverage.len() / w.max(1);
+    let mut outline = vec![0; coverage.len()];
+
+    for (i, c) in coverage.iter().enumerate().filter(|(_, c)| **c > 0) {
+        let (x, y) = ((i % w) as isize, (i / w) as isize);
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let (nx, ny) = (x + dx, y + dy);
+                if dx * dx + dy * dy > r * r
+                    || nx < 0
+                    || ny < 0
+                    || nx as usize >= w
+                    || ny as usize >= h
+                {
+                    continue;
+                }
+                let o = &mut outline[nx as usize + ny as usize * w];
+                *o = (*o).max(*c);
+            }
+        }
+    }
+    outline
+}
+
+// Move coverage by `(dx, dy)` pixels, whatever ends up outside is dropped.
+fn shift(coverage: &[u8], w: u16, (dx, dy): (i16, i16)) -> Vec<u8>
+{
+    let w = w as isize;
+    let h = coverage.len() as isize / w.max(1);
+    let mut shifted = vec![0; coverage.len()];
+    for (i, c) in coverage.iter().enumerate().filter(|(_, c)| **c > 0) {
+        let (x, y) =
+            (i as isize % w + dx as isize, i as isize / w + dy as isize);
+        if x >= 0 && y >= 0 && x < w && y < h {
+            shifted[(x + y * w) as usize] = *c;
+        }
+    }
+    shifted
+}
+
+// Height of a bar that's just tall enough for the wrapped text.
+pub fn fitted_bar_height(
+    w: u16,
+    text: &str,
+    options: &CaptionOptions,
+) -> Result<u16, CaptionError>
+{
+    let px = options.font_size.ok_or_else(|| {
+        CaptionError::Layout(
+            "fitting the bar to the text needs a font size".into(),
+        )
+    })?;
+    let fonts = StyledFonts::new(options)?;
+    let spans = spans(text, options);
+    let px = font_size_for(&fonts, options, (0, 0), &spans, Some(px))?;
+    let max_width = area(w, 0, options).0 as f32;
+
+    let glyphs =
+        layout_text(&fonts, options, (0.0, 0.0), (max_width, None), px, &spans);
+    let (_, height) = measure(&glyphs);
+    let margin = margin(w, 0, options);
+    Ok((height.ceil() as u16)
+        .saturating_add(margin.top)
+        .saturating_add(margin.bottom))
+}
+
+// Check whether the text of a caption bar fits, without drawing it.
+pub fn bar_overflow(
+    w: u16,
+    height: u16,
+    text: Option<&str>,
+    options: &CaptionOptions,
+) -> Result<Option<Overflow>, CaptionError>
+{
+    let text = match text {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let fonts = StyledFonts::new(options)?;
+    let spans = spans(text, options);
+    let space = area(w, height, options);
+    let px = font_size_for(&fonts, options, space, &spans, options.font_size)?;
+
+    let glyphs = layout_text(
+        &fonts,
+        options,
+        (0.0, 0.0),
+        (space.0 as f32, None),
+        px,
+        &spans,
+    );
+    let (width, text_height) = measure(&glyphs);
+    if width <= space.0 as f32 && text_height <= space.1 as f32 {
+        return Ok(None);
+    }
+
+    let line_height = fonts.fonts[0]
+        .horizontal_line_metrics(px)
+        .map(|m| m.new_line_size)
+        .unwrap_or(px)
+        * options.line_height;
+    let lines = count_lines(&glyphs);
+    let suggested_font_size = match fits(&fonts, options, space, MIN_PX, &spans)
+    {
+        true => Some(fit_font_size(&fonts, options, space, &spans)),
+        false => None,
+    };
+    // the margin grows with the bar, so look for the first height that works
+    let suggested_bar_height = match width <= space.0 as f32 {
+        true => (text_height.ceil() as u16..=u16::MAX).find(|h| {
+            let (area_w, area_h) = area(w, *h, options);
+            area_w as f32 >= width && area_h as f32 >= text_height
+        }),
+        false => None,
+    };
+
+    Ok(Some(Overflow {
+        lines,
+        lines_fit: lines.min((space.1 as f32 / line_height) as usize),
+        font_size: px,
+        suggested_font_size,
+        suggested_bar_height,
+    }))
+}
+
+// Space kept free around the text: the padding the options ask for, the
+// padding of bars fitted to the text, or a margin that grows with the bar.
+fn margin(w: u16, height: u16, options: &CaptionOptions) -> Padding
+{
+    match (options.padding, options.bar_height) {
+        (Some(padding), _) => padding,
+        (None, BarHeight::Fit { padding }) => Padding::all(padding),
+        _ => Padding::all((w.min(height) as f32 * MARGIN) as u16),
+    }
+}
+
+// The size of a bar without its margins.
+fn area(w: u16, height: u16, options: &CaptionOptions) -> (u16, u16)
+{
+    let margin = margin(w, height, options);
+    (
+        w.saturating_sub(margin.left.saturating_add(margin.right)),
+        height.saturating_sub(margin.top.saturating_add(margin.bottom)),
+    )
+}
+
+// if a px_size is provided, we use that, otherwise we look for the biggest
+// size the text fits at
+fn font_size_for(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    area: (u16, u16),
+    spans: &[Span],
+    font_size: Option<f32>,
+) -> Result<f32, CaptionError>
+{
+    let px = match font_size {
+        Some(px) => px,
+        None => fit_font_size(fonts, options, area, spans),
+    };
+    if !px.is_normal() || px < 0.0 {
+        return Err(CaptionError::Layout(format!("invalid font size {}", px)));
+    }
+    Ok(px)
+}
+
+// Lay the text out into the area starting at `origin`, wrapped into lines no
+// wider than `max_width` and aligned the way the options ask. Every character
+// is drawn with the first of the fonts for its style that has a glyph for it,
+// or the first font if none do. Each glyph keeps the style of its span.
+fn layout_text(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    origin: (f32, f32),
+    max_size: (f32, Option<f32>),
+    px: f32,
+    spans: &[Span],
+) -> Vec<Glyph>
+{
+    // fontdue wraps by its own advances, without letter spacing or shaping,
+    // so lines it makes too wide are wrapped again a bit narrower
+    let mut wrap_width = max_size.0;
+    let mut lines = set_lines(fonts, options, wrap_width, px, spans);
+    for _ in 0..REWRAP_RETRIES {
+        let widest = lines.iter().map(|line| line.width).fold(0.0, f32::max);
+        if widest <= max_size.0 {
+            break;
+        }
+        wrap_width = (wrap_width - (widest - max_size.0)).max(0.0);
+        lines = set_lines(fonts, options, wrap_width, px, spans);
+    }
+    arrange(lines, options, origin, max_size)
+}
+
+// Wrap the text and set every line on its own. Words too wide for a line are
+// broken first. Lines are split into runs of one direction, which are split
+// again into pieces of one style and font. Those are shaped in logical order,
+// so letters join up, and placed side by side in visual order.
+fn set_lines(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    max_width: f32,
+    px: f32,
+    spans: &[Span],
+) -> Vec<Line>
+{
+    let spans = match options.wrap {
+        Wrap::Word => hyphenate::break_words(
+            spans,
+            max_width,
+            &fonts.patterns,
+            |grapheme, style| {
+                fonts.advance(grapheme, style, px, options.letter_spacing)
+            },
+        ),
+        Wrap::Letter => spans.to_vec(),
+    };
+    let text: String = spans.iter().map(|span| span.text.as_str()).collect();
+    let styles: Vec<Style> = spans
+        .iter()
+        .flat_map(|span| vec![span.style; span.text.len()])
+        .collect();
+
+    let wrapped = wrap_lines(fonts, options.wrap, max_width, px, &spans);
+    let ranges: Vec<_> =
+        wrapped.iter().map(|(range, _, _)| range.clone()).collect();
+    let runs = bidi::line_runs(&text, &ranges);
+
+    let mut lines = Vec::new();
+    for ((_, baseline, wrapped), runs) in wrapped.into_iter().zip(runs) {
+        let mut glyphs = Vec::new();
+        let mut pen = 0.0;
+        let mut spaced = false;
+        for (run, rtl) in runs.runs {
+            let mut pieces = Vec::new();
+            let mut piece_start = run.start;
+            for i in (run.start + 1..run.end).chain(std::iter::once(run.end)) {
+                if i < run.end && styles[i] == styles[piece_start] {
+                    continue;
+                }
+                let style = styles[piece_start];
+                for (r, font_index) in
+                    font_runs(fonts, &text[piece_start..i], &style)
+                {
+                    let r = piece_start + r.start..piece_start + r.end;
+                    pieces.push((r, font_index, style));
+                }
+                piece_start = i;
+            }
+            if rtl {
+                pieces.reverse();
+            }
+
+            for (piece, font_index, style) in pieces {
+                let font = fonts.fonts[font_index];
+                let px = px * style.scale;
+                let text = &text[piece];
+                for glyph in shape(fonts, font_index, text, px, rtl) {
+                    let glyph_index = glyph.glyph_index;
+                    let metrics = font.metrics_indexed(glyph_index as usize, px);
+                    glyphs.push(Glyph {
+                        x: pen + glyph.offset.0 + metrics.xmin as f32,
+                        y: -glyph.offset.1
+                            - (metrics.height as f32 + metrics.ymin as f32),
+                        width: metrics.width,
+                        height: metrics.height,
+                        font_index,
+                        glyph_index,
+                        px,
+                        parent: text[glyph.cluster..]
+                            .chars()
+                            .next()
+                            .unwrap_or(' '),
+                        style,
+                        line: 0,
+                    });
+                    pen += glyph.advance;
+                    // marks don't move the pen, and aren't spaced out
+                    if glyph.advance > 0.0 {
+                        pen += options.letter_spacing;
+                        spaced = true;
+                    }
+                }
+            }
+        }
+        lines.push(Line {
+            glyphs,
+            width: match spaced {
+                true => pen - options.letter_spacing,
+                false => pen,
+            },
+            baseline,
+            wrapped,
+            rtl: runs.rtl,
+        });
+    }
+    lines
+}
+
+// The glyphs for a piece of text drawn with one font, in visual order.
+fn shape(
+    fonts: &StyledFonts,
+    font_index: usize,
+    text: &str,
+    px: f32,
+    rtl: bool,
+) -> Vec<Shaped>
+{
+    #[cfg(feature = "shaping")]
+    if let Some(face) = &fonts.faces[font_index] {
+        return shaping::shape(face, text, px, rtl);
+    }
+    shaping::unshaped(fonts.fonts[font_index], text, px, rtl)
+}
+
+// Put the lines on their baselines, spaced out by the line height, and align
+// them in the area starting at `origin`.
+fn arrange(
+    lines: Vec<Line>,
+    options: &CaptionOptions,
+    origin: (f32, f32),
+    (max_width, max_height): (f32, Option<f32>),
+) -> Vec<Glyph>
+{
+    let first = lines.first().map(|line| line.baseline).unwrap_or(0.0);
+    let mut glyphs = Vec::new();
+    for (n, line) in lines.into_iter().enumerate() {
+        let baseline = first + (line.baseline - first) * options.line_height;
+        let free = max_width - line.width;
+        let spaces = line
+            .glyphs
+            .iter()
+            .filter(|glyph| glyph.parent.is_whitespace())
+            .count();
+        // the last line of a justified paragraph goes where it starts
+        let align = match (options.align, line.wrapped, line.rtl) {
+            (TextAlign::Justify, false, false) => TextAlign::Left,
+            (TextAlign::Justify, false, true) => TextAlign::Right,
+            (align, _, _) => align,
+        };
+        let (left, gap) = match align {
+            TextAlign::Left => (0.0, 0.0),
+            TextAlign::Center => (free / 2.0, 0.0),
+            TextAlign::Right => (free, 0.0),
+            TextAlign::Justify if spaces > 0 && free > 0.0 => {
+                (0.0, free / spaces as f32)
+            }
+            TextAlign::Justify => (0.0, 0.0),
+        };
+
+        let mut spaces = 0;
+        for mut glyph in line.glyphs {
+            if glyph.parent.is_whitespace() {
+                spaces += 1;
+            }
+            glyph.x += origin.0 + left + gap * spaces as f32;
+            glyph.y += baseline;
+            glyph.line = n;
+            glyphs.push(glyph);
+        }
+    }
+
+    // the drawn text is aligned, not the lines it's on
+    let (top, bottom) = match ink(&glyphs) {
+        Some((_, top, _, bottom)) => (top, bottom),
+        None => return glyphs,
+    };
+    let dy = match (options.vertical_align, max_height) {
+        (VerticalAlign::Middle, Some(h)) => (h - (bottom - top)) / 2.0,
+        (VerticalAlign::Bottom, Some(h)) => h - (bottom - top),
+        _ => 0.0,
+    };
+    for glyph in &mut glyphs {
+        glyph.y += origin.1 + dy - top;
+    }
+    glyphs
+}
+
+// Wrap the text of all spans into lines no wider than `max_width` with
+// fontdue. Returns the byte range of every line, without the line break or
+// space it ends with, the baseline fontdue put it on, and whether it was
+// wrapped.
+fn wrap_lines(
+    fonts: &StyledFonts,
+    wrap: Wrap,
+    max_width: f32,
+    px: f32,
+    spans: &[Span],
+) -> Vec<(Range<usize>, f32, bool)>
+{
+    let text: String = spans.iter().map(|span| span.text.as_str()).collect();
+    let mut layout = layout_spans(fonts, wrap, max_width, px, spans);
+
+    let mut lines = Vec::new();
+    let line = |range: Range<usize>, baseline, wrapped| {
+        let end = range.start + text[range.clone()].trim_end().len();
+        (range.start..end, baseline, wrapped)
+    };
+    // fontdue has a glyph for every character, so they can be walked
+    // together. A line break belongs to the line it ends
+    let (mut start, mut baseline, mut hard) = (0, None::<f32>, false);
+    for (glyph, (i, c)) in layout.glyphs().iter().zip(text.char_indices()) {
+        let y = match c.is_control() || shaping::ignorable(c) {
+            true => glyph.y,
+            false => {
+                let metrics = fonts.fonts[glyph.key.font_index].metrics_indexed(
+                    glyph.key.glyph_index as usize,
+                    glyph.key.px,
+                );
+                glyph.y + glyph.height as f32 + metrics.ymin as f32
+            }
+        };
+        match baseline {
+            Some(b) if (b - y).abs() < 0.5 => {}
+            Some(b) => {
+                lines.push(line(start..i, b, !hard));
+                start = i;
+                baseline = Some(y);
+            }
+            None => baseline = Some(y),
+        }
+        hard = c == '\n';
+    }
+    if let Some(b) = baseline {
+        lines.push(line(start..text.len(), b, false));
+    }
+    lines
+}
+
+fn layout_spans(
+    fonts: &StyledFonts,
+    wrap: Wrap,
+    max_width: f32,
+    px: f32,
+    spans: &[Span],
+) -> Layout<Style>
+{
+    let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
+    layout.reset(&LayoutSettings {
+        x: 0.0,
+        y: 0.0,
+        max_width: Some(max_width),
+        max_height: None,
+        horizontal_align: HorizontalAlign::Left,
+        vertical_align: layout::VerticalAlign::Top,
+        wrap_style: match wrap {
+            Wrap::Word => WrapStyle::Word,
+            Wrap::Letter => WrapStyle::Letter,
+        },
+        wrap_hard_breaks: true,
+    });
+    for span in spans {
+        let px = px * span.style.scale;
+        for (run, font_index) in font_runs(fonts, &span.text, &span.style) {
+            // fontdue gives control characters no width, which is what
+            // ignorable ones get when they're drawn
+            let text: String = span.text[run]
+                .chars()
+                .map(|c| match shaping::ignorable(c) {
+                    true => '\0',
+                    false => c,
+                })
+                .collect();
+            layout.append(
+                &fonts.fonts,
+                &TextStyle::with_user_data(
+                    &text,
+                    px,
+                    font_index,
+                    span.style,
+                ),
+            );
+        }
+    }
+    layout
+}
+
+// Split text into runs of characters drawn with the same font. Spaces,
+// ignorable characters and others without a glyph in any font stay in the
+// current run.
+fn font_runs(
+    fonts: &StyledFonts,
+    text: &str,
+    style: &Style,
+) -> Vec<(Range<usize>, usize)>
+{
+    let mut runs = Vec::new();
+    let first = fonts.candidates(style).next().unwrap_or(0);
+    let (mut start, mut current) = (0, first);
+    for (i, c) in text.char_indices() {
+        let font_index = match c.is_whitespace() || shaping::ignorable(c) {
+            true => None,
+            false => fonts
+                .candidates(style)
+                .find(|f| fonts.fonts[*f].lookup_glyph_index(c) != 0),
+        };
+        match font_index {
+            Some(font_index) if font_index != current => {
+                if i > start {
+                    runs.push((start..i, current));
+                }
+                start = i;
+                current = font_index;
+            }
+            _ => {}
+        }
+    }
+    if start < text.len() {
+        runs.push((start..text.len(), current));
+    }
+    runs
+}
+
+// Left, top, right and bottom of the box around every glyph of the layout,
+// None if nothing is drawn.
+fn ink(glyphs: &[Glyph]) -> Option<(f32, f32, f32, f32)>
+{
+    let (mut left, mut top) = (f32::MAX, f32::MAX);
+    let (mut right, mut bottom) = (f32::MIN, f32::MIN);
+    for glyph in glyphs.iter().filter(|g| g.width > 0 && g.height > 0) {
+        left = left.min(glyph.x);
+        top = top.min(glyph.y);
+        right = right.max(glyph.x + glyph.width as f32);
+        bottom = bottom.max(glyph.y + glyph.height as f32);
+    }
+    match left > right {
+        true => None,
+        false => Some((left, top, right, bottom)),
+    }
+}
+
+// Width and height of the box around every glyph of the layout.
+fn measure(glyphs: &[Glyph]) -> (f32, f32)
+{
+    match ink(glyphs) {
+        Some((left, top, right, bottom)) => (right - left, bottom - top),
+        None => (0.0, 0.0),
+    }
+}
+
+fn count_lines(glyphs: &[Glyph]) -> usize
+{
+    glyphs.iter().map(|glyph| glyph.line + 1).max().unwrap_or(0)
+}
+
+fn fits(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    (w, h): (u16, u16),
+    px: f32,
+    spans: &[Span],
+) -> bool
+{
+    let glyphs =
+        layout_text(fonts, options, (0.0, 0.0), (w as f32, None), px, spans);
+    let (width, height) = measure(&glyphs);
+    width <= w as f32 && height <= h as f32
+}
+
+// Whether every word is narrow enough for a line without being broken.
+fn words_fit(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    w: u16,
+    px: f32,
+    spans: &[Span],
+) -> bool
+{
+    spans.iter().all(|span| {
+        hyphenate::words(&span.text).into_iter().all(|(_, word)| {
+            let width: f32 = word
+                .graphemes(true)
+                .map(|g| {
+                    fonts.advance(g, &span.style, px, options.letter_spacing)
+                })
+                .sum();
+            width <= w as f32
+        })
+    })
+}
+
+// A guess at the biggest font size the text fits into the area at, from the
+// room its grapheme clusters take up at one pixel: all of them on one line
+// have to fit into the area, and the widest one into a line. Wrapping only
+// ever takes more room, so the text rarely fits at a bigger size.
+fn estimate_font_size(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    (w, h): (u16, u16),
+    spans: &[Span],
+) -> f32
+{
+    let line_height = fonts.fonts[0]
+        .horizontal_line_metrics(ESTIMATE_PX)
+        .map(|m| m.new_line_size)
+        .unwrap_or(ESTIMATE_PX)
+        * options.line_height
+        / ESTIMATE_PX;
+    let (mut room, mut widest) = (0.0, 0.0_f32);
+    for span in spans {
+        for grapheme in span.text.graphemes(true) {
+            let advance = fonts.advance(grapheme, &span.style, ESTIMATE_PX, 0.0)
+                / ESTIMATE_PX;
+            room += advance * line_height * span.style.scale;
+            widest = widest.max(advance);
+        }
+    }
+    (w as f32 * h as f32 / room).sqrt().min(w as f32 / widest)
+}
+
+// The biggest font size, in whole pixels, the wrapped text fits into the area
+// at. Wrapping makes this not strictly monotonic, but close enough for a
+// binary search, which starts at the estimated size. Sizes that don't have to
+// break words are preferred.
+fn fit_font_size(
+    fonts: &StyledFonts,
+    options: &CaptionOptions,
+    (w, h): (u16, u16),
+    spans: &[Span],
+) -> f32
+{
+    let whole = words_fit(fonts, options, w, MIN_PX, spans);
+    let fits = |px: f32| {
+        (!whole || words_fit(fonts, options, w, px, spans))
+            && fits(fonts, options, (w, h), px, spans)
+    };
+
+    let (mut lo, mut hi) = (MIN_PX as u16, h.max(MIN_PX as u16));
+    let guess = (estimate_font_size(fonts, options, (w, h), spans) as u16)
+        .clamp(lo, hi);
+    if !fits(guess as f32) {
+        hi = guess;
+    } else if guess == hi || fits(hi as f32) {
+        return hi as f32;
+    } else {
+        lo = guess;
+    }
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if fits(mid as f32) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    lo as f32
+}
+
+// Generate the section to prepend by fitting some text into
+// the designated area. The result still needs to be mapped to palette
+// indices. Fonts with colour bitmaps draw those instead of their outlines,
+// and spans with a colour of their own go into the colour layer.
+fn make_piece(
+    (fonts, colour_fonts): (&StyledFonts, &[Option<&ColourGlyphs>]),
+    options: &CaptionOptions,
+    (piece_width, piece_height): (u16, u16),
+    margin: Padding,
+    px: f32,
+    spans: &[Span],
+) -> Result<Piece, CaptionError>
+{
+    let (w, h) = (piece_width as usize, piece_height as usize);
+    let glyphs = layout_text(
+        fonts,
+        options,
+        (margin.left as f32, margin.top as f32),
+        (
+            piece_width.saturating_sub(margin.left.saturating_add(margin.right))
+                as f32,
+            Some(
+                piece_height
+                    .saturating_sub(margin.top.saturating_add(margin.bottom))
+                    as f32,
+            ),
+        ),
+        px,
+        spans,
+    );
+    let mut canvas = vec![0; w * h];
+    let mut colour = Vec::new();
+    let mut span_colours: Vec<[u8; 3]> =
+        spans.iter().filter_map(|span| span.style.colour).collect();
+    span_colours.sort_unstable();
+    span_colours.dedup();
+
+    // now draw 🔫 ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+    for glyph in glyphs {
+        let (style, px) = (glyph.style, glyph.px);
+        let image = colour_fonts[glyph.font_index]
+            .and_then(|glyphs| glyphs.rasterize(GlyphId(glyph.glyph_index), px));
+        if let Some(image) = image {
+            if colour.is_empty() {
+                colour = vec![[0; 4]; w * h];
+            }
+            // bitmap only glyphs have no outline, which puts them on the
+            // baseline
+            let x0 = (glyph.x + image.left).round() as isize;
+            let y0 = (glyph.y + image.top).round() as isize;
+            for (i, pixel) in image.pixels.iter().enumerate() {
+                let x = x0 + (i % image.width) as isize;
+                let y = y0 + (i / image.width) as isize;
+                if pixel[3] == 0
+                    || x < 0
+                    || y < 0
+                    || x as usize >= w
+                    || y as usize >= h
+                {
+                    continue;
+                }
+                colour[x as usize + y as usize * w] = *pixel;
+            }
+            continue;
+        }
+        if glyph.width == 0 {
+            continue;
+        }
+        let (x0, y0) = (glyph.x.round() as isize, glyph.y.round() as isize);
+        let (_, bitmap) = fonts.fonts[glyph.font_index]
+            .rasterize_indexed(glyph.glyph_index as usize, px);
+        if style.colour.is_some() && colour.is_empty() {
+            colour = vec![[0; 4]; w * h];
+        }
+
+        // fake bold by drawing the glyph a few times side by side, and
+        // italic by shifting rows further right the higher up they are
+        let (bold, italic) = fonts.faux(glyph.font_index, &style);
+        let weight = match bold {
+            true => (px / FAUX_BOLD_PX).ceil() as isize,
+            false => 0,
+        };
+        for (i, pixel) in bitmap.iter().enumerate().filter(|(_, p)| **p > 0) {
+            let row = i / glyph.width;
+            let slant = match italic {
+                true => ((glyph.height - row) as f32 * FAUX_SLANT) as isize,
+                false => 0,
+            };
+            for dx in 0..=weight {
+                let x = x0 + (i % glyph.width) as isize + slant + dx;
+                let y = y0 + row as isize;
+                // anything outside of the piece is clipped
+                if x < 0 || y < 0 || x as usize >= w || y as usize >= h {
+                    continue;
+                }
+                let i = x as usize + y as usize * w;
+                match style.colour {
+                    Some(c) if *pixel >= colour[i][3] => {
+                        colour[i] = [c[0], c[1], c[2], *pixel];
+                    }
+                    Some(_) => {}
+                    None => canvas[i] = canvas[i].max(*pixel),
+                }
+            }
+        }
+    }
+
+    Ok(Piece {
+        coverage: canvas,
+        colour,
+        span_colours,
+        ..Piece::default()
+    })
+}
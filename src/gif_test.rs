@@ -1,7 +1,12 @@
 use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::Arc;
 
-use crate::gif_processor;
+use crate::gif_processor::{
+    self, BarHeight, CaptionError, CaptionMode, CaptionOptions, CaptionPosition,
+    ColourCompression, FontRegistry, OverflowPolicy, Padding, TextAlign,
+    VerticalAlign, Wrap,
+};
 
 #[test]
 fn sample_gifs()
@@ -13,13 +18,1070 @@ fn sample_gifs()
     }
     let mut out_image = File::create("result.gif").unwrap();
     let out = gif_processor::caption(
-        "test",
         buf.as_slice(),
-        &String::from(" "),
-        gif_processor::ColourCompression::Wu(8),
-        None,
-        None,
-        //Some(60.0),
+        &test_options(" ").compression(ColourCompression::Wu(8)),
+        //.font_size(60.0),
+    )
+    .unwrap();
+    out_image.write_all(&out).unwrap();
+}
+
+// A small two frame gif with a global black and white palette.
+fn tiny_gif() -> Vec<u8>
+{
+    let mut out = Vec::new();
+    {
+        let mut encoder =
+            gif::Encoder::new(&mut out, 4, 4, &[0, 0, 0, 255, 255, 255])
+                .unwrap();
+        for i in 0..2 {
+            let frame = gif::Frame {
+                width: 4,
+                height: 4,
+                buffer: vec![i; 16].into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    out
+}
+
+// A gif of one frame of a single palette index.
+fn solid_gif(w: u16, h: u16, palette: &[u8], index: u8) -> Vec<u8>
+{
+    let mut out = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut out, w, h, palette).unwrap();
+        let frame = gif::Frame {
+            width: w,
+            height: h,
+            buffer: vec![index; w as usize * h as usize].into(),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+    }
+    out
+}
+
+// A registry of just the test font, so the tests don't need the
+// `default-font` feature.
+fn test_fonts() -> FontRegistry
+{
+    let mut fonts = FontRegistry::empty();
+    fonts
+        .register(
+            "DejaVu Sans",
+            include_bytes!("../fonts/test/DejaVuSans.ttf"),
+        )
+        .unwrap();
+    fonts
+}
+
+thread_local! {
+    // parsing the font once per test thread is plenty
+    static TEST_FONTS: Arc<FontRegistry> = Arc::new(test_fonts());
+}
+
+// Options for a caption in the test font.
+fn test_options<S: Into<String>>(text: S) -> CaptionOptions
+{
+    CaptionOptions::new(text).fonts(TEST_FONTS.with(Arc::clone))
+}
+
+// The global palette of a gif, empty if it has none, and its frames.
+fn decoded(out: &[u8]) -> (Vec<u8>, Vec<gif::Frame<'static>>)
+{
+    let mut decoder = gif::DecodeOptions::new().read_info(out).unwrap();
+    let palette = decoder.global_palette().unwrap_or(&[]).to_vec();
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        frames.push(frame.clone());
+    }
+    (palette, frames)
+}
+
+// The colour of a palette index.
+fn rgb(palette: &[u8], i: u8) -> [u8; 3]
+{
+    let i = i as usize * 3;
+    [palette[i], palette[i + 1], palette[i + 2]]
+}
+
+#[test]
+fn truncated_gif_is_an_error()
+{
+    let buf = tiny_gif();
+    let out = gif_processor::caption(
+        &buf[..buf.len() / 2],
+        &test_options("truncated"),
+    );
+    assert!(matches!(out, Err(gif_processor::CaptionError::Decode(_))));
+}
+
+#[test]
+fn local_palettes_are_deduped()
+{
+    let mut buf = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut buf, 4, 4, &[]).unwrap();
+        for palette in [[255, 0, 0, 0, 255, 0], [0, 255, 0, 0, 0, 255]] {
+            let frame = gif::Frame {
+                width: 4,
+                height: 4,
+                palette: Some(palette.to_vec()),
+                buffer: [0, 1].repeat(8).into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    let out =
+        gif_processor::caption(buf.as_slice(), &test_options("dedup")).unwrap();
+
+    let (global, frames) = decoded(&out);
+    let mut colours = Vec::new();
+    for frame in frames {
+        assert!(frame.palette.is_none());
+        // the last two pixels come from the original frame
+        for idx in &frame.buffer[frame.buffer.len() - 2..] {
+            colours.push(rgb(&global, *idx));
+        }
+    }
+    assert_eq!(
+        colours,
+        [[255, 0, 0], [0, 255, 0], [0, 255, 0], [0, 0, 255]]
+    );
+}
+
+#[test]
+fn deduped_transparency_stays_apart()
+{
+    let mut buf = Vec::new();
+    {
+        // the transparent black is the same colour as the opaque one
+        let palette = [0, 0, 0, 0, 0, 0, 255, 255, 255];
+        let mut encoder = gif::Encoder::new(&mut buf, 2, 2, &palette).unwrap();
+        let frames = [
+            (Some(0), None, vec![0, 1, 2, 1]),
+            (None, Some(vec![255, 255, 255, 0, 0, 0]), vec![0, 1, 0, 1]),
+        ];
+        for (transparent, palette, buffer) in frames {
+            let frame = gif::Frame {
+                width: 2,
+                height: 2,
+                transparent,
+                palette,
+                buffer: buffer.into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    let options = test_options("I").scale(1.0);
+    let out = gif_processor::caption(&buf, &options).unwrap();
+
+    let (palette, frames) = decoded(&out);
+    let frame = &frames[0];
+    assert!(frame.palette.is_none());
+    let t = frame.transparent.unwrap();
+    // below the 2x2 bar only the first pixel is see-through
+    let pixels = &frame.buffer[4..];
+    assert_eq!(pixels[0], t);
+    assert!(pixels[1..].iter().all(|x| *x != t));
+    let colours: Vec<[u8; 3]> =
+        pixels[1..].iter().map(|x| rgb(&palette, *x)).collect();
+    assert_eq!(colours, [[0; 3], [255; 3], [0; 3]]);
+}
+
+#[test]
+fn local_palettes_get_black_and_white()
+{
+    let mut buf = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut buf, 4, 4, &[]).unwrap();
+        // 400 distinct colours between both frames, too many to merge
+        for offset in [0, 200] {
+            let palette = (offset..offset + 200)
+                .flat_map(|x: u32| [(x % 256) as u8, (x / 256) as u8, 7])
+                .collect();
+            let frame = gif::Frame {
+                width: 4,
+                height: 4,
+                dispose: gif::DisposalMethod::Background,
+                palette: Some(palette),
+                buffer: vec![0; 16].into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    let out =
+        gif_processor::caption(buf.as_slice(), &test_options("I").scale(1.0))
+            .unwrap();
+
+    for frame in decoded(&out).1 {
+        let palette = frame.palette.as_ref().unwrap();
+        // the caption bar is the top half of the frame
+        for idx in &frame.buffer[..16] {
+            let colour = rgb(palette, *idx);
+            assert!(colour == [0; 3] || colour == [255; 3]);
+        }
+    }
+}
+
+#[test]
+fn empty_first_frame_gets_the_bars()
+{
+    // the second frame brings a palette of its own, if it's given one
+    let gif = |palette: Option<Vec<u8>>| {
+        let mut buf = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut buf, 4, 4, &[0, 0, 0, 255, 255, 255])
+                    .unwrap();
+            encoder.write_frame(&gif::Frame::default()).unwrap();
+            let frame = gif::Frame {
+                width: 4,
+                height: 4,
+                palette,
+                buffer: vec![1; 16].into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        buf
+    };
+    let local = Some(vec![255, 0, 0, 0, 0, 255]);
+
+    for (buf, compression) in [
+        (gif(None), ColourCompression::None),
+        (gif(local.clone()), ColourCompression::None),
+        (gif(local), ColourCompression::Wu(2)),
+    ] {
+        let options = test_options("I").scale(1.0).compression(compression);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let frames = decoded(&out).1;
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].width, frames[0].height), (4, 8));
+    }
+
+    // overlays pick their colour against nothing at all
+    let options = test_options("I").mode(CaptionMode::Overlay).scale(0.5);
+    let out = gif_processor::caption(&gif(None), &options).unwrap();
+    assert_eq!(decoded(&out).1.len(), 2);
+}
+
+#[test]
+fn frames_below_the_canvas_are_cut_off()
+{
+    // more colours than Wu keeps below
+    let palette = [0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255];
+    let below = |top: u16| {
+        let mut buf = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut buf, 4, 4, &palette).unwrap();
+            let frame = gif::Frame {
+                top,
+                width: 4,
+                height: 4,
+                buffer: vec![1; 16].into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        buf
+    };
+    let options = test_options("I").scale(1.0);
+
+    let out = gif_processor::caption(&below(100), &options).unwrap();
+    assert_eq!(decoded(&out).1[0].buffer.len(), 4 * 8);
+    // unless the bars would push them past the largest gif there can be
+    assert!(matches!(
+        gif_processor::caption(&below(u16::MAX), &options),
+        Err(CaptionError::Unsupported(_))
+    ));
+    // composited frames are always cut down to the canvas
+    let options = options.compression(ColourCompression::Wu(2));
+    assert!(gif_processor::caption(&below(u16::MAX), &options).is_ok());
+}
+
+#[test]
+fn compositor_restores_previous()
+{
+    use gif::{DisposalMethod, Frame};
+
+    let mut compositor = gif_processor::Compositor::new(2, 2);
+    let red = [255, 0, 0, 255];
+    let blue = [0, 0, 255, 255];
+    let frames = [
+        (0, 0, 2, red.repeat(4), DisposalMethod::Keep),
+        (1, 1, 1, blue.to_vec(), DisposalMethod::Previous),
+        (0, 0, 1, vec![0; 4], DisposalMethod::Keep),
+        (1, 0, 0, vec![], DisposalMethod::Keep),
+    ];
+    let mut canvases = Vec::new();
+    for (left, top, size, buffer, dispose) in frames {
+        let frame = Frame {
+            left,
+            top,
+            width: size,
+            height: size,
+            dispose,
+            buffer: buffer.into(),
+            ..Frame::default()
+        };
+        canvases.push(compositor.composite(&frame).to_vec());
+    }
+    assert_eq!(canvases[1], [red, red, red, blue].concat());
+    assert_eq!(canvases[2], red.repeat(4));
+    // empty frames leave the canvas as it was
+    assert_eq!(canvases[3], canvases[2]);
+}
+
+#[test]
+fn wu_keeps_transparency()
+{
+    let mut buf = Vec::new();
+    {
+        // eight colours, the first of which is transparent
+        let palette: Vec<u8> = (0..24).map(|x| x * 10).collect();
+        let mut encoder = gif::Encoder::new(&mut buf, 4, 2, &palette).unwrap();
+        let frame = gif::Frame {
+            width: 4,
+            height: 2,
+            transparent: Some(0),
+            buffer: vec![0, 1, 2, 3, 4, 5, 6, 7].into(),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+    }
+    let out = gif_processor::caption(
+        buf.as_slice(),
+        &test_options("I")
+            .compression(ColourCompression::Wu(4))
+            .scale(1.0),
+    )
+    .unwrap();
+
+    let frame = &decoded(&out).1[0];
+    let transparent = frame.transparent.unwrap();
+    // below the 4x2 bar, only the first pixel is transparent
+    let pixels = &frame.buffer[8..];
+    assert_eq!(pixels[0], transparent);
+    assert!(pixels[1..].iter().all(|x| *x != transparent));
+}
+
+#[test]
+fn partial_first_frame_keeps_its_caption()
+{
+    // a frame in the middle of the canvas, without any transparency
+    let partial = |palette: &[u8]| -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut buf, 40, 40, palette).unwrap();
+            let frame = gif::Frame {
+                left: 10,
+                top: 10,
+                width: 20,
+                height: 20,
+                buffer: vec![0; 400].into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        buf
+    };
+    let red_black_white = [255, 0, 0, 0, 0, 0, 255, 255, 255];
+    // every index is a colour, but most aren't used by the frame
+    let full: Vec<u8> = (0..=255).flat_map(|x| [x, x, x]).collect();
+
+    for palette in [&red_black_white[..], &full] {
+        let buf = partial(palette);
+        let options = test_options("hello").text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let frame = &decoded(&out).1[0];
+        let t = frame.transparent.unwrap();
+        let top = frame.height as usize - 40;
+        let bar = &frame.buffer[..top * 40];
+
+        // the bar has text on it, none of which is see-through, and the
+        // canvas around the frame is
+        assert!(bar.iter().all(|x| *x != t));
+        assert!(bar.iter().any(|x| *x != bar[0]));
+        assert_eq!(frame.buffer[top * 40], t);
+        assert_ne!(frame.buffer[(top + 20) * 40 + 20], t);
+    }
+}
+
+#[test]
+fn stream_matches_caption()
+{
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    let buf = tiny_gif();
+    let options = test_options("stream").compression(ColourCompression::Wu(2));
+    let expected = gif_processor::caption(&buf, &options).unwrap();
+
+    // the gif doesn't have to start at the beginning of the input
+    let mut input = Cursor::new([b"junk".as_ref(), &buf].concat());
+    input.seek(SeekFrom::Start(4)).unwrap();
+    let mut out = Vec::new();
+    gif_processor::caption_stream(input, &mut out, &options).unwrap();
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn bars_go_above_and_below()
+{
+    let buf = solid_gif(4, 4, &[255, 0, 0], 0);
+
+    for (position, height, gif_rows) in [
+        (CaptionPosition::Top, 8, 4..8),
+        (CaptionPosition::Bottom, 8, 0..4),
+        (CaptionPosition::Both, 12, 4..8),
+    ] {
+        let options = test_options("top")
+            .bottom_text("bottom")
+            .position(position)
+            .scale(1.0);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+
+        let (palette, frames) = decoded(&out);
+        assert_eq!(frames[0].height, height);
+        for (row, pixels) in frames[0].buffer.chunks(4).enumerate() {
+            let in_gif = gif_rows.contains(&row);
+            let red = |x: &u8| rgb(&palette, *x) == [255, 0, 0];
+            assert!(pixels.iter().all(|x| red(x) == in_gif));
+        }
+    }
+}
+
+#[test]
+fn overlay_keeps_size()
+{
+    let buf = solid_gif(40, 40, &[255, 0, 0], 0);
+    let options = test_options("hi")
+        .mode(CaptionMode::Overlay)
+        .text_colour([255; 3])
+        .outline(1, [0, 0, 255]);
+    let out = gif_processor::caption(&buf, &options).unwrap();
+
+    let (palette, frames) = decoded(&out);
+    let frame = &frames[0];
+    assert_eq!((frame.width, frame.height), (40, 40));
+    assert!(frame.palette.is_none());
+
+    // text and outline end up on top of the red frame
+    let colours: Vec<[u8; 3]> =
+        frame.buffer.iter().map(|i| rgb(&palette, *i)).collect();
+    assert!(colours.contains(&[255, 255, 255]));
+    assert!(colours.contains(&[0, 0, 255]));
+    assert!(colours.contains(&[255, 0, 0]));
+}
+
+#[test]
+fn smooth_font_uses_greys()
+{
+    let buf = solid_gif(40, 40, &[0, 0, 0, 255, 255, 255], 0);
+    let options = test_options("smooth").smooth_font(true);
+    let out = gif_processor::caption(&buf, &options).unwrap();
+
+    let (palette, frames) = decoded(&out);
+    let grey = |i: &u8| {
+        let colour = rgb(&palette, *i);
+        colour[0] == colour[1] && colour[0] > 0 && colour[0] < 255
+    };
+    assert!(frames[0].buffer.iter().any(grey));
+}
+
+#[test]
+fn long_captions_fit_inside_the_bar()
+{
+    let buf = solid_gif(100, 100, &[0, 0, 0, 255, 255, 255], 0);
+    let text = "a caption far too long to fit on one line of such a small gif";
+    let out = gif_processor::caption(&buf, &test_options(text)).unwrap();
+
+    let (palette, frames) = decoded(&out);
+    let white = |i: u8| rgb(&palette, i) == [255; 3];
+    let bar: Vec<&[u8]> = frames[0].buffer.chunks(100).take(30).collect();
+
+    // nothing is drawn on the edges of the bar, but there is text
+    assert!(bar.iter().all(|row| white(row[0]) && white(row[99])));
+    assert!(bar[0].iter().chain(bar[29]).all(|x| white(*x)));
+    assert!(bar.iter().flat_map(|row| row.iter()).any(|x| !white(*x)));
+}
+
+#[test]
+fn overflow_is_reported_or_grows_the_bar()
+{
+    let buf = solid_gif(100, 100, &[0, 0, 0, 255, 255, 255], 0);
+    let options = test_options("far too many words for this bar")
+        .font_size(20.0)
+        .overflow(OverflowPolicy::Fail);
+
+    let (top, bottom) = gif_processor::caption_overflow(&buf, &options).unwrap();
+    let top = top.unwrap();
+    assert!(bottom.is_none());
+    assert!(top.lines_fit < top.lines);
+    match gif_processor::caption(&buf, &options) {
+        Err(CaptionError::Overflow(overflow)) => assert_eq!(overflow, top),
+        _ => panic!("overflow was not reported"),
+    }
+
+    let options = options.overflow(OverflowPolicy::Grow);
+    let out = gif_processor::caption(&buf, &options).unwrap();
+    let decoder = gif::DecodeOptions::new().read_info(&*out).unwrap();
+    let grown = top.suggested_bar_height.unwrap();
+    assert!(grown > 30);
+    assert_eq!(decoder.height(), 100 + grown);
+}
+
+#[test]
+fn bar_heights_from_text_and_pixels()
+{
+    let buf = solid_gif(100, 400, &[0, 0, 0, 255, 255, 255], 0);
+    let height = |options: CaptionOptions| {
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let decoder = gif::DecodeOptions::new().read_info(&*out).unwrap();
+        decoder.height() - 400
+    };
+
+    let fitted = height(
+        test_options("hi")
+            .font_size(20.0)
+            .bar_height(BarHeight::Fit { padding: 5 }),
     );
-    out_image.write(&out).unwrap();
+    assert!(fitted > 10 && fitted <= 30);
+    let taller = height(
+        test_options("hi\nthere")
+            .font_size(20.0)
+            .bar_height(BarHeight::Fit { padding: 5 }),
+    );
+    assert!(taller > fitted);
+
+    assert_eq!(
+        height(test_options("hi").bar_height(BarHeight::Pixels(50))),
+        50
+    );
+    assert_eq!(
+        height(test_options("hi").clamp_bar_height(None, Some(40))),
+        40
+    );
+    assert_eq!(
+        height(
+            test_options("hi")
+                .bar_height(BarHeight::Pixels(5))
+                .clamp_bar_height(Some(20), None)
+        ),
+        20
+    );
+}
+
+#[test]
+fn fonts_are_chosen_by_name()
+{
+    let mut fonts = FontRegistry::empty();
+    let options = CaptionOptions::new("hi").fonts(Arc::new(fonts.clone()));
+    assert!(gif_processor::caption(&tiny_gif(), &options).is_err());
+
+    fonts
+        .register("dejavu", include_bytes!("../fonts/test/DejaVuSans.ttf"))
+        .unwrap();
+    assert!(fonts.register("broken", &[0, 1, 2]).is_err());
+    let options = options.fonts(Arc::new(fonts));
+    assert!(gif_processor::caption(&tiny_gif(), &options).is_ok());
+
+    let options = options.font("dejavu");
+    assert!(gif_processor::caption(&tiny_gif(), &options).is_ok());
+    let options = options.font("comic sans");
+    match gif_processor::caption(&tiny_gif(), &options) {
+        Err(CaptionError::Layout(_)) => {}
+        _ => panic!("missing font was not reported"),
+    }
+}
+
+#[cfg(feature = "default-font")]
+#[test]
+fn default_font_is_compiled_in()
+{
+    let fonts = FontRegistry::default();
+    assert_eq!(fonts.names().count(), 1);
+    let options = CaptionOptions::new("hi");
+    assert!(gif_processor::caption(&tiny_gif(), &options).is_ok());
+}
+
+#[test]
+fn missing_glyphs_come_from_fallback_fonts_in_order()
+{
+    // both have nothing but U+1F7E5, drawn red by one and blue by the other.
+    // The test font comes first, it's registered first
+    let mut registry = test_fonts();
+    registry
+        .register("red", include_bytes!("../fonts/test/square-sbix.ttf"))
+        .unwrap();
+    registry
+        .register("blue", include_bytes!("../fonts/test/square-cbdt.ttf"))
+        .unwrap();
+    let registry = Arc::new(registry);
+    // colour glyphs use the closest colour the palette has
+    let palette = [0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255];
+    let buf = solid_gif(60, 60, &palette, 0);
+
+    // the colours in the bar, the test font draws the text black on white
+    let colours = |fonts: &Arc<FontRegistry>, fallback: &[&str]| {
+        let options = test_options("hi \u{1f7e5}")
+            .fonts(fonts.clone())
+            .fallback_fonts(fallback.iter().copied())
+            .font_size(16.0)
+            .text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let frame = &frames[0];
+        let bar = &frame.buffer[..(frame.height as usize - 60) * 60];
+        let mut colours: Vec<[u8; 3]> =
+            bar.iter().map(|i| rgb(&palette, *i)).collect();
+        colours.sort_unstable();
+        colours.dedup();
+        colours
+    };
+
+    let (red, blue) = ([255, 0, 0], [0, 0, 255]);
+    let red_first = colours(&registry, &["red", "blue"]);
+    assert!(red_first.contains(&[0; 3]));
+    assert!(red_first.contains(&red) && !red_first.contains(&blue));
+    let blue_first = colours(&registry, &["blue", "red"]);
+    assert!(blue_first.contains(&blue) && !blue_first.contains(&red));
+    // without either the square has nothing to be drawn with
+    let neither = colours(&Arc::new(test_fonts()), &[]);
+    assert!(!neither.contains(&red) && !neither.contains(&blue));
+}
+
+#[test]
+fn colour_glyphs_come_from_bitmap_fonts()
+{
+    // fonts with nothing but a 16px strike for U+1F7E5, a red square in the
+    // sbix one and a blue square in the CBDT one
+    let fonts = [
+        (
+            &include_bytes!("../fonts/test/square-sbix.ttf")[..],
+            [255u8, 0, 0],
+        ),
+        (
+            &include_bytes!("../fonts/test/square-cbdt.ttf")[..],
+            [0, 0, 255],
+        ),
+    ];
+    // more greys than Wu keeps
+    let greys: Vec<u8> = (0..64u8).flat_map(|i| [i * 4; 3]).collect();
+    let buf = solid_gif(60, 60, &greys, 0);
+
+    for (bytes, colour) in fonts {
+        let mut registry = test_fonts();
+        registry.register("squares", bytes).unwrap();
+        let options = test_options("\u{1f7e5}")
+            .fonts(Arc::new(registry))
+            .fallback_fonts(["squares"])
+            .font_size(16.0)
+            .compression(ColourCompression::Wu(8));
+        let out = gif_processor::caption(&buf, &options).unwrap();
+
+        let (palette, frames) = decoded(&out);
+        let frame = &frames[0];
+        let bar = &frame.buffer[..(frame.height as usize - 60) * 60];
+        // the square gets a palette slot of its own colour from Wu
+        let close = |i: &u8| {
+            rgb(&palette, *i)
+                .iter()
+                .zip(colour.iter())
+                .all(|(a, b)| (*a as i32 - *b as i32).abs() < 32)
+        };
+        assert!(bar.iter().filter(|i| close(i)).count() > 150);
+    }
+}
+
+#[test]
+fn colour_glyphs_without_a_size_are_skipped()
+{
+    // give the sbix square's only strike 0 pixels per em, which can't be
+    // scaled to any font size
+    let mut bytes = include_bytes!("../fonts/test/square-sbix.ttf").to_vec();
+    let be32 = |b: &[u8], at: usize| {
+        u32::from_be_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) as usize
+    };
+    let tables = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+    let sbix = (0..tables)
+        .map(|i| 12 + i * 16)
+        .find(|at| &bytes[*at..*at + 4] == b"sbix")
+        .map(|at| be32(&bytes, at + 8))
+        .unwrap();
+    let strike = sbix + be32(&bytes, sbix + 8);
+    bytes[strike..strike + 2].copy_from_slice(&[0, 0]);
+
+    let mut registry = test_fonts();
+    registry.register("squares", &bytes).unwrap();
+    let buf = solid_gif(60, 60, &[0, 0, 0, 255, 255, 255, 255, 0, 0], 0);
+    let options = test_options("\u{1f7e5}")
+        .fonts(Arc::new(registry))
+        .fallback_fonts(["squares"])
+        .font_size(16.0);
+    let out = gif_processor::caption(&buf, &options).unwrap();
+    let (palette, frames) = decoded(&out);
+    assert!(!frames[0]
+        .buffer
+        .iter()
+        .any(|i| rgb(&palette, *i) == [255, 0, 0]));
+}
+
+#[test]
+fn bars_get_outlines_and_shadows()
+{
+    let buf = solid_gif(100, 100, &[0, 0, 0, 255, 255, 255], 0);
+
+    for compression in [ColourCompression::None, ColourCompression::Wu(16)] {
+        let options = test_options("styled")
+            .compression(compression)
+            .outline(2, [255, 0, 0])
+            .shadow((3, 3), [0, 0, 255]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+
+        let (palette, frames) = decoded(&out);
+        let colours: Vec<[u8; 3]> = frames[0].buffer[..3000]
+            .iter()
+            .map(|i| rgb(&palette, *i))
+            .collect();
+        assert!(colours.contains(&[255, 0, 0]));
+        assert!(colours.contains(&[0, 0, 255]));
+    }
+}
+
+#[test]
+fn bars_use_the_chosen_colours()
+{
+    // enough colours that Wu has to squash them, with room for two more
+    let palette: Vec<u8> =
+        (0..128u8).flat_map(|i| [i * 2, 255 - i * 2, i]).collect();
+    let mut buf = Vec::new();
+    {
+        let mut encoder =
+            gif::Encoder::new(&mut buf, 100, 100, &palette).unwrap();
+        let frame = gif::Frame {
+            width: 100,
+            height: 100,
+            buffer: (0..10000).map(|i| (i % 128) as u8).collect(),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+    }
+
+    for compression in [ColourCompression::None, ColourCompression::Wu(16)] {
+        let options = test_options("colours")
+            .compression(compression)
+            .text_colour([255, 255, 0])
+            .background_colour([0, 0, 128]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+
+        let (palette, frames) = decoded(&out);
+        let colours: Vec<[u8; 3]> = frames[0].buffer[..3000]
+            .iter()
+            .map(|i| rgb(&palette, *i))
+            .collect();
+        assert_eq!(colours[0], [0, 0, 128]);
+        assert!(colours.contains(&[255, 255, 0]));
+    }
+}
+
+#[test]
+fn text_colour_contrasts_with_the_background()
+{
+    // a dark grey stands out against white, a light one doesn't
+    let palette = [40, 40, 40, 200, 200, 200, 230, 230, 230, 255, 255, 255];
+    let buf = solid_gif(120, 60, &palette, 0);
+
+    let text_colours = |options: &CaptionOptions| -> Vec<[u8; 3]> {
+        let out = gif_processor::caption(&buf, options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let mut colours: Vec<[u8; 3]> = frames[0].buffer[..120 * 18]
+            .iter()
+            .map(|i| rgb(&palette, *i))
+            .collect();
+        colours.sort_unstable();
+        colours.dedup();
+        colours
+    };
+
+    // the palette's darkest colour on a white bar, unless it's asked for
+    // more contrast than that has
+    let bar = test_options("contrast");
+    assert!(text_colours(&bar).contains(&[40; 3]));
+    assert!(text_colours(&bar.clone().min_contrast(21.0)).contains(&[0; 3]));
+
+    // overlays pick against the dark grey frame
+    let overlay = test_options("contrast")
+        .mode(CaptionMode::Overlay)
+        .outline(0, [0; 3]);
+    assert!(text_colours(&overlay).contains(&[255; 3]));
+}
+
+#[test]
+fn markup_colours_and_emphasises_spans()
+{
+    let buf = solid_gif(120, 60, &[0, 0, 0, 255, 255, 255], 1);
+    let bar = |options: &CaptionOptions| -> Vec<[u8; 3]> {
+        let out = gif_processor::caption(&buf, options).unwrap();
+        let (palette, frames) = decoded(&out);
+        frames[0].buffer[..120 * 18]
+            .iter()
+            .map(|i| rgb(&palette, *i))
+            .collect()
+    };
+
+    for compression in [ColourCompression::None, ColourCompression::Wu(16)] {
+        let options = test_options("{red}so{/} *very* _funny_")
+            .font_size(14.0)
+            .compression(compression);
+        // without markup the braces are just text
+        assert!(!bar(&options).contains(&[255, 0, 0]));
+        let styled = bar(&options.clone().markup(true));
+        assert!(styled.contains(&[255, 0, 0]));
+        assert!(styled.contains(&[0, 0, 0]));
+    }
+
+    // faux bold covers more of the bar than regular text
+    let ink = |text: &str| {
+        let options = test_options(text).font_size(14.0).markup(true);
+        bar(&options).iter().filter(|c| **c != [255; 3]).count()
+    };
+    assert!(ink("*bold*") > ink("bold"));
+}
+
+#[test]
+fn markup_sizes_are_bounded()
+{
+    let buf = solid_gif(120, 60, &[0, 0, 0, 255, 255, 255], 1);
+    let bar = |text: &str| -> Vec<u8> {
+        let options = test_options(text).font_size(2.0).markup(true);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        decoded(&out).1[0].buffer.to_vec()
+    };
+    // nested sizes multiply, but never past ten times or a tenth of the font
+    assert_eq!(bar("{1000x}{1000x}a"), bar("{10x}a"));
+    assert_eq!(bar("{5x}{0.001x}a"), bar("{0.1x}a"));
+}
+
+#[test]
+fn right_to_left_text_is_reordered()
+{
+    let buf = solid_gif(120, 40, &[0, 0, 0, 255, 255, 255], 0);
+    let bar = |text: &str| -> Vec<u8> {
+        let options = test_options(text).font_size(10.0);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        decoded(&out).1[0].buffer.to_vec()
+    };
+    // a right to left paragraph puts the latin text on the left, which is
+    // where it already is in a left to right one
+    assert_eq!(bar("\u{5d0} abc"), bar("abc \u{5d0}"));
+    assert_ne!(bar("x abc"), bar("abc x"));
+}
+
+#[cfg(feature = "shaping")]
+#[test]
+fn shaping_joins_ligatures()
+{
+    let buf = solid_gif(120, 40, &[0, 0, 0, 255, 255, 255], 0);
+    let bar = |text: &str| -> Vec<u8> {
+        let options = test_options(text).font_size(20.0);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        decoded(&out).1[0].buffer.to_vec()
+    };
+    // a zero width non-joiner keeps the letters apart without taking space
+    assert_ne!(bar("fi"), bar("f\u{200c}i"));
+}
+
+#[cfg(feature = "shaping")]
+#[test]
+fn shaping_joins_arabic_letters()
+{
+    let buf = solid_gif(120, 40, &[0, 0, 0, 255, 255, 255], 1);
+    // how many columns of the bar the text covers
+    let width = |text: &str| {
+        let options = test_options(text).font_size(20.0).text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let h = frames[0].height as usize - 40;
+        let dark = |x: usize, y: usize| {
+            rgb(&palette, frames[0].buffer[x + y * 120])[0] < 128
+        };
+        (0..120).filter(|x| (0..h).any(|y| dark(*x, y))).count()
+    };
+    // joined up letters take their narrower connecting forms, letters kept
+    // apart by non-joiners don't
+    assert!(
+        width("\u{628}\u{64a}\u{62a}")
+            < width("\u{628}\u{200c}\u{64a}\u{200c}\u{62a}")
+    );
+}
+
+#[cfg(feature = "shaping")]
+#[test]
+fn shaped_lines_are_wrapped_to_the_bar()
+{
+    // heh and dal joined up are wider than fontdue measures them on their own
+    let word = "\u{647}\u{62f}".repeat(3);
+    let text = [word.as_str(); 6].join(" ");
+    for w in [80, 150] {
+        let buf = solid_gif(w, 20, &[0, 0, 0, 255, 255, 255], 1);
+        let options = test_options(text.as_str())
+            .font_size(20.0)
+            .padding(Padding::all(10))
+            .bar_height(BarHeight::Pixels(200))
+            .text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let w = w as usize;
+        let dark = |x: usize, y: usize| {
+            rgb(&palette, frames[0].buffer[x + y * w])[0] < 128
+        };
+        // nothing is drawn into the padding
+        let columns: Vec<usize> =
+            (0..w).filter(|x| (0..200).any(|y| dark(*x, y))).collect();
+        assert!(!columns.is_empty());
+        assert!(columns.iter().all(|x| *x >= 10 && *x < w - 10));
+    }
+}
+
+#[test]
+fn text_is_aligned_and_padded()
+{
+    let buf = solid_gif(120, 60, &[0, 0, 0, 255, 255, 255], 1);
+    // left, top, right and bottom of the text in the bar
+    let ink = |options: CaptionOptions| {
+        let options = options
+            .font_size(12.0)
+            .bar_height(BarHeight::Pixels(40))
+            .text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let mut bounds = (usize::MAX, usize::MAX, 0, 0);
+        for (i, p) in frames[0].buffer[..120 * 40].iter().enumerate() {
+            if rgb(&palette, *p)[0] < 128 {
+                let (x, y) = (i % 120, i / 120);
+                bounds.0 = bounds.0.min(x);
+                bounds.1 = bounds.1.min(y);
+                bounds.2 = bounds.2.max(x);
+                bounds.3 = bounds.3.max(y);
+            }
+        }
+        bounds
+    };
+
+    let left = ink(test_options("hi").align(TextAlign::Left));
+    let center = ink(test_options("hi"));
+    let right = ink(test_options("hi").align(TextAlign::Right));
+    assert!(left.0 < center.0 && center.0 < right.0);
+    assert!(left.0 < 12 && right.2 > 108);
+
+    let padded = ink(test_options("hi")
+        .align(TextAlign::Left)
+        .vertical_align(VerticalAlign::Bottom)
+        .padding(Padding {
+            top: 0,
+            right: 0,
+            bottom: 5,
+            left: 30,
+        }));
+    assert!(padded.0 >= 30 && padded.0 < 34);
+    assert_eq!(padded.3, 34);
+    let top = ink(test_options("hi").vertical_align(VerticalAlign::Top));
+    assert!(top.1 < 5);
+
+    // lines further apart take up more of the bar
+    let lines = |line_height| {
+        let (_, top, _, bottom) =
+            ink(test_options("hi\nho").line_height(line_height));
+        bottom - top
+    };
+    assert!(lines(1.5) > lines(1.0));
+    // and spaced out letters more of its width
+    let (l, _, r, _) = ink(test_options("hi").letter_spacing(6.0));
+    assert!(r - l > center.2 - center.0);
+}
+
+#[test]
+fn overlong_words_are_broken()
+{
+    let buf = solid_gif(60, 20, &[0, 0, 0, 255, 255, 255], 1);
+    // height of the bar, the columns with text in them and how many pixels
+    // the text covers. Spaced out letters don't overlap
+    let bar = |options: CaptionOptions| {
+        let options = options
+            .font_size(12.0)
+            .letter_spacing(1.0)
+            .bar_height(BarHeight::Fit { padding: 4 })
+            .text_colour([0; 3]);
+        let out = gif_processor::caption(&buf, &options).unwrap();
+        let (palette, frames) = decoded(&out);
+        let frame = &frames[0];
+        let h = frame.height as usize - 20;
+        let dark = |x: usize, y: usize| {
+            rgb(&palette, frame.buffer[x + y * 60])[0] < 128
+        };
+        let columns: Vec<usize> =
+            (0..60).filter(|x| (0..h).any(|y| dark(*x, y))).collect();
+        let ink = (0..60 * h).filter(|i| dark(i % 60, i / 60)).count();
+        (h, columns, ink)
+    };
+
+    let (short, _, _) = bar(test_options("AAAH"));
+    let word = "AAAAAAAAAAAAAAAAAAAAAAAAH";
+    let (long, columns, hyphenated) = bar(test_options(word));
+    // the word goes over a few lines instead of running out of the bar
+    assert!(long > 2 * short);
+    assert!(columns.iter().all(|x| *x >= 4 && *x < 56));
+    // with hyphens where it's broken, which breaking anywhere doesn't draw
+    let (_, _, letters) = bar(test_options(word).wrap(Wrap::Letter));
+    assert!(hyphenated > letters);
+    // a word in the middle of a sentence is broken too
+    let (sentence, _, _) = bar(test_options("me: AAAAAAAAAAAAAAAAAAAAAAAAH ok"));
+    assert!(sentence > long);
+}
+
+#[cfg(feature = "hyphenate")]
+#[test]
+fn words_are_hyphenated_by_language()
+{
+    let buf = solid_gif(80, 40, &[0, 0, 0, 255, 255, 255], 0);
+    let bar = |options: &CaptionOptions| -> Vec<u8> {
+        let out = gif_processor::caption(&buf, options).unwrap();
+        decoded(&out).1[0].buffer.to_vec()
+    };
+    let options = test_options("hyphenation")
+        .font_size(12.0)
+        .bar_height(BarHeight::Pixels(60))
+        .padding(Padding::all(2));
+    // en-us only breaks hy-phen-a-tion, the last point that fits is after
+    // the a, where breaking at any grapheme would fit more of the word
+    let hyphenated = bar(&options.clone().language("en-us"));
+    assert_eq!(hyphenated, bar(&options.clone().text("hyphena-\ntion")));
+    assert_ne!(hyphenated, bar(&options));
+    assert!(matches!(
+        gif_processor::caption(&buf, &options.language("xx")),
+        Err(CaptionError::Layout(_))
+    ));
+}
+
+#[test]
+fn joiners_and_selectors_take_no_room()
+{
+    let buf = solid_gif(100, 100, &[0, 0, 0, 255, 255, 255], 0);
+    let bar = |text: &str| -> Vec<u8> {
+        let out = gif_processor::caption(&buf, &test_options(text)).unwrap();
+        decoded(&out).1[0].buffer.to_vec()
+    };
+    // the text is sized and drawn as if they weren't there
+    let hidden =
+        "a cap\u{200d}tion lo\u{fe0f}ng\u{e0100} enough\u{e0067} to wrap";
+    assert_eq!(bar("a caption long enough to wrap"), bar(hidden));
 }
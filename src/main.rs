@@ -1,4 +1,9 @@
-use gif_processor::ColourCompression;
+use std::sync::Arc;
+
+use gif_processor::{
+    BarHeight, CaptionError, CaptionMode, CaptionOptions, CaptionPosition,
+    ColourCompression, OverflowPolicy, Shadow, TextAlign,
+};
 use gloo_file::Blob;
 use yew::services::reader::{File, FileData, ReaderService, ReaderTask};
 use yew::services::ConsoleService;
@@ -17,6 +22,8 @@ pub enum Msg
 {
     File(File),
     Loaded(FileData),
+    FontFile(File),
+    FontLoaded(FileData),
     Opt(Opts),
     Compression,
     Start,
@@ -27,28 +34,37 @@ pub enum Msg
 pub enum Opts
 {
     Caption(String),
+    BottomCaption(String),
+    Position(CaptionPosition),
+    Mode(CaptionMode),
+    Align(TextAlign),
+    LineHeight(f32),
+    LetterSpacing(f32),
+    Language(String),
+    TextColour(Option<[u8; 3]>),
+    BackgroundColour([u8; 3]),
+    OutlineWidth(Option<u16>),
+    Shadow,
+    SmoothFont,
+    Markup,
+    GrowBar,
+    FitBar,
     Scale(f32),
     FontSize(f32),
     //Compression(bool),
     NumberColours(u8),
 }
 
-#[derive(Default)]
-struct OptStruct
-{
-    caption: String,
-    scale: Option<f32>,
-    font_size: Option<f32>,
-    number_colours: ColourCompression,
-}
-
 pub struct Model
 {
     link: ComponentLink<Self>,
     filedata: Option<FileData>,
-    opts: OptStruct,
+    opts: CaptionOptions,
     pending: Option<ReaderTask>, // no way to create default ReaderTask
-    result: Option<Blob>, // should bre replaced with Result?
+    pending_font: Option<ReaderTask>,
+    result: Option<Result<Blob, CaptionError>>,
+    // captions that don't fit, found before encoding
+    warnings: Vec<String>,
     url: String,
     compression: VNode,
 }
@@ -63,9 +79,11 @@ impl Component for Model
         Self {
             link,
             filedata: None,
-            opts: OptStruct::default(),
+            opts: CaptionOptions::default(),
             pending: None,// Vec::with_capacity(1),
+            pending_font: None,
             result: None,
+            warnings: Vec::new(),
             url: String::default(),
             compression: html!(),
         }
@@ -91,21 +109,102 @@ impl Component for Model
                 self.pending = Some(task);
                 false
             }
+            Msg::FontFile(file) => {
+                let task = ReaderService::read_file(
+                    file,
+                    self.link.callback(Msg::FontLoaded),
+                )
+                .unwrap();
+                self.pending_font = Some(task);
+                false
+            }
+            Msg::FontLoaded(filedata) => {
+                self.pending_font = None;
+                // registries are shared between options, so copy on write
+                let fonts = Arc::make_mut(&mut self.opts.fonts);
+                match fonts.register(filedata.name.as_str(), &filedata.content) {
+                    Ok(()) => self.opts.font = Some(filedata.name),
+                    Err(err) => self.result = Some(Err(err)),
+                }
+                true
+            }
             Msg::Opt(opt) => {
                 match opt {
                     Opts::Caption(caption) => {
-                        self.opts.caption = caption;
+                        self.opts.text = caption;
+                        return true
+                    }
+                    Opts::BottomCaption(caption) => {
+                        self.opts.bottom_text = caption;
+                    }
+                    Opts::Position(position) => {
+                        self.opts.position = position;
+                        return true
+                    }
+                    Opts::Mode(mode) => {
+                        self.opts.mode = mode;
                         return true
                     }
+                    Opts::Align(align) => {
+                        self.opts.align = align;
+                    }
+                    Opts::LineHeight(line_height) => {
+                        self.opts.line_height = line_height;
+                    }
+                    Opts::LetterSpacing(spacing) => {
+                        self.opts.letter_spacing = spacing;
+                    }
+                    Opts::Language(code) => {
+                        self.opts.language =
+                            if code.is_empty() { None } else { Some(code) }
+                    }
+                    Opts::TextColour(colour) => {
+                        self.opts.text_colour = colour;
+                    }
+                    Opts::BackgroundColour(colour) => {
+                        self.opts.background_colour = colour;
+                    }
+                    Opts::OutlineWidth(width) => {
+                        self.opts.outline_width = width;
+                    }
+                    Opts::Shadow => {
+                        self.opts.shadow = match self.opts.shadow {
+                            Some(_) => None,
+                            None => Some(Shadow {
+                                offset: (2, 2),
+                                colour: [0; 3],
+                            }),
+                        };
+                    }
+                    Opts::SmoothFont => {
+                        self.opts.smooth_font = !self.opts.smooth_font;
+                    }
+                    Opts::Markup => {
+                        self.opts.markup = !self.opts.markup;
+                    }
+                    Opts::GrowBar => {
+                        self.opts.overflow = match self.opts.overflow {
+                            OverflowPolicy::Grow => OverflowPolicy::Clip,
+                            _ => OverflowPolicy::Grow,
+                        };
+                    }
+                    Opts::FitBar => {
+                        self.opts.bar_height = match self.opts.bar_height {
+                            BarHeight::Fit { .. } => BarHeight::default(),
+                            _ => BarHeight::Fit { padding: 8 },
+                        };
+                    }
                     Opts::Scale(scale) => {
-                        self.opts.scale = Some((scale - 1.0).clamp(0.1, 3.0))
+                        self.opts.bar_height =
+                            BarHeight::Scale((scale - 1.0).clamp(0.1, 3.0))
                     }
                     Opts::FontSize(size) => {
                         self.opts.font_size =
                             if size > 0.0 { Some(size) } else { None }
                     }
                     Opts::NumberColours(num) => {
-                        self.opts.number_colours = ColourCompression::Wu(num.clamp(4,255));
+                        self.opts.compression =
+                            ColourCompression::Wu(num.clamp(4, 255));
                     }
                 }
                 false
@@ -126,7 +225,7 @@ impl Component for Model
                     );
                 } else {
                     self.compression = html!();
-                    self.opts.number_colours = ColourCompression::None;
+                    self.opts.compression = ColourCompression::None;
                 }
                 true
             }
@@ -139,28 +238,43 @@ impl Component for Model
             Msg::Start => {
                 //self.result.clear();
                 let filedata = self.filedata.as_ref().unwrap();
-                ConsoleService::log(&format!("{:?}", self.opts.number_colours));
+                ConsoleService::log(&format!("{:?}", self.opts.compression));
+                self.warnings = match gif_processor::caption_overflow(
+                    filedata.content.as_slice(),
+                    &self.opts,
+                ) {
+                    Ok((top, bottom))
+                        if self.opts.overflow != OverflowPolicy::Grow =>
+                    {
+                        top.iter().chain(&bottom).map(|x| x.to_string()).collect()
+                    }
+                    _ => Vec::new(),
+                };
                 let processed = gif_processor::caption(
-                    &filedata.name,
                     filedata.content.as_slice(),
-                    &self.opts.caption,
-                    self.opts.number_colours,
-                    self.opts.scale,
-                    self.opts.font_size,
-                );
-                let blob = Blob::new_with_options(
-                    processed.as_slice(),
-                    Some("image/gif"),
+                    &self.opts,
                 );
-                self.result = Some(blob);
+                self.result = Some(processed.map(|processed| {
+                    Blob::new_with_options(
+                        processed.as_slice(),
+                        Some("image/gif"),
+                    )
+                }));
                 self.link.callback(|_| Msg::Complete).emit(());
                 false
             }
             Msg::Complete => {
-                ConsoleService::log("Done");
-                if let Some(blob) = &self.result {
-                    self.url =
-                        Url::create_object_url_with_blob(blob.as_ref()).unwrap();
+                match &self.result {
+                    Some(Ok(blob)) => {
+                        ConsoleService::log("Done");
+                        self.url =
+                            Url::create_object_url_with_blob(blob.as_ref())
+                                .unwrap();
+                    }
+                    Some(Err(err)) => {
+                        ConsoleService::error(&err.to_string());
+                    }
+                    None => {}
                 }
                 true
             }
@@ -199,6 +313,21 @@ impl Component for Model
                     />
                 </div>
 
+                <div class="form-div">
+                    <label>{ "Font: " }</label>
+                    <input
+                        type="file" accept=".ttf,.otf"
+                        onchange=self.link.callback(move |value| {
+                            if let ChangeData::Files(files) = value {
+                                if let Some(file) = files.item(0) {
+                                    return Msg::FontFile(file)
+                                }
+                            }
+                            Msg::NoOp
+                        })
+                    />
+                </div>
+
                 <div class="form-div">
                     <label>{ "Caption" }</label>
                     <input
@@ -209,6 +338,156 @@ impl Component for Model
                     />
                 </div>
 
+                <div class="form-div">
+                    <label>{ "Position" }</label>
+                    <select
+                        onchange=self.link.callback(|e: ChangeData| {
+                            if let ChangeData::Select(select) = e {
+                                let position = match select.value().as_str() {
+                                    "bottom" => CaptionPosition::Bottom,
+                                    "both" => CaptionPosition::Both,
+                                    _ => CaptionPosition::Top,
+                                };
+                                return Msg::Opt(Opts::Position(position))
+                            }
+                            Msg::NoOp
+                        })
+                    >
+                        <option value="top">{ "Top" }</option>
+                        <option value="bottom">{ "Bottom" }</option>
+                        <option value="both">{ "Both" }</option>
+                    </select>
+                </div>
+
+                {
+                    if self.opts.position == CaptionPosition::Both {
+                        html!(
+                        <div class="form-div">
+                            <label>{ "Bottom caption" }</label>
+                            <input
+                                type="text" id="bottom-caption"
+                                oninput=self.link.callback(|e: InputData| {
+                                    Msg::Opt(Opts::BottomCaption(e.value))
+                                })
+                            />
+                        </div>
+                        )
+                    } else {
+                        html!()
+                    }
+                }
+
+                <div class="form-div">
+                    <label>{ "Mode" }</label>
+                    <select
+                        onchange=self.link.callback(|e: ChangeData| {
+                            if let ChangeData::Select(select) = e {
+                                let mode = match select.value().as_str() {
+                                    "overlay" => CaptionMode::Overlay,
+                                    _ => CaptionMode::Bar,
+                                };
+                                return Msg::Opt(Opts::Mode(mode))
+                            }
+                            Msg::NoOp
+                        })
+                    >
+                        <option value="bar">{ "Bar" }</option>
+                        <option value="overlay">{ "Overlay" }</option>
+                    </select>
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Align" }</label>
+                    <select
+                        onchange=self.link.callback(|e: ChangeData| {
+                            if let ChangeData::Select(select) = e {
+                                let align = match select.value().as_str() {
+                                    "left" => TextAlign::Left,
+                                    "right" => TextAlign::Right,
+                                    "justify" => TextAlign::Justify,
+                                    _ => TextAlign::Center,
+                                };
+                                return Msg::Opt(Opts::Align(align))
+                            }
+                            Msg::NoOp
+                        })
+                    >
+                        <option value="center">{ "Center" }</option>
+                        <option value="left">{ "Left" }</option>
+                        <option value="right">{ "Right" }</option>
+                        <option value="justify">{ "Justify" }</option>
+                    </select>
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Line height" }</label>
+                    <input
+                        type="number" value="1" step="0.1" min="0.5"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::LineHeight(e.value.parse().unwrap_or(1.0)))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Letter spacing" }</label>
+                    <input
+                        type="number" value="0"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::LetterSpacing(e.value.parse().unwrap_or(0.0)))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Hyphenation language" }</label>
+                    <input
+                        type="text" placeholder="en-us"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::Language(e.value.trim().to_string()))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Text colour" }</label>
+                    <input
+                        type="color"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::TextColour(parse_colour(&e.value)))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Bar colour" }</label>
+                    <input
+                        type="color" value="#ffffff"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::BackgroundColour(
+                                parse_colour(&e.value).unwrap_or([255; 3]),
+                            ))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Outline width" }</label>
+                    <input
+                        type="number" placeholder="auto" min="0"
+                        oninput=self.link.callback(|e: InputData| {
+                            Msg::Opt(Opts::OutlineWidth(e.value.parse().ok()))
+                        })
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Drop shadow" }</label>
+                    <input type="checkbox"
+                    onclick=self.link.callback(|_| Msg::Opt(Opts::Shadow))
+                    />
+                </div>
+
                 <div class="form-div">
                     <label>{ "Scale" }</label>
                     <input
@@ -230,6 +509,34 @@ impl Component for Model
                     />
                 </div>
 
+                <div class="form-div">
+                    <label>{ "Fit bar to text" }</label>
+                    <input type="checkbox"
+                    onclick=self.link.callback(|_| Msg::Opt(Opts::FitBar))
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Grow bar to fit" }</label>
+                    <input type="checkbox"
+                    onclick=self.link.callback(|_| Msg::Opt(Opts::GrowBar))
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "Smooth text" }</label>
+                    <input type="checkbox"
+                    onclick=self.link.callback(|_| Msg::Opt(Opts::SmoothFont))
+                    />
+                </div>
+
+                <div class="form-div">
+                    <label>{ "*Bold*, _italic_ and {red}colours{/}" }</label>
+                    <input type="checkbox"
+                    onclick=self.link.callback(|_| Msg::Opt(Opts::Markup))
+                    />
+                </div>
+
                 <div class="form-div">
                     <label>{ "Colour compression" }</label>
                     <input type="checkbox"
@@ -242,13 +549,25 @@ impl Component for Model
                 <div class="form-div">
                     <input
                         type="button" value="Submit"
-                        disabled=self.filedata.is_none() || self.opts.caption.len().lt(&1)
+                        disabled=self.filedata.is_none() || self.opts.text.len().lt(&1)
                         onclick=self.link.callback(|_| Msg::Start)/>
                 </div>
 
                 </form>
             </div>
             <div>
+            {
+                for self.warnings.iter().map(|warning| {
+                    html!(<p class="warning">{ warning }</p>)
+                })
+            }
+            {
+                if let Some(Err(err)) = &self.result {
+                    html!(<p class="error">{ err.to_string() }</p>)
+                } else {
+                    html!()
+                }
+            }
             <img src={ self.url.to_string() } />
             </div>
             </div>
@@ -256,6 +575,17 @@ impl Component for Model
     }
 }
 
+// "#rrggbb" from a colour input
+fn parse_colour(hex: &str) -> Option<[u8; 3]>
+{
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
 fn main()
 {
     yew::start_app::<Model>();